@@ -0,0 +1,331 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: blocking calls (file I/O, CPU heavy work, ...) made from a task block the
+//       executor thread, and every other task with it. `spawn_blocking` runs them
+//       on a pool of threads instead, started on demand and stopped once idle
+
+use {
+    crate::{
+        join_handle::{join_closure, Abort, JoinHandle},
+        local_executor,
+    },
+    std::{
+        cell::RefCell,
+        collections::VecDeque,
+        sync::{Arc, Condvar, Mutex, OnceLock, Weak},
+        thread,
+        time::Duration,
+    },
+};
+
+/// Default maximum number of threads of a pool
+const MAX_THREADS: usize = 512;
+
+/// Default time a thread waits for a new closure before it stops
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the blocking `f` on the blocking pool of the current executor,
+/// or on a process-wide pool if there is none.
+///
+/// The returned handle can be awaited for the output of `f`. Aborting it only
+/// cancels `f` if it hasn't started yet, a blocking call can't be interrupted.
+pub fn spawn_blocking<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> JoinHandle<T> {
+    Handle::current().spawn_blocking(f)
+}
+
+/// A closure waiting for a thread of the pool
+struct BlockingTask {
+    /// `None` once taken by a thread, or aborted
+    f: Mutex<Option<Box<dyn FnOnce() + Send>>>,
+}
+
+impl BlockingTask {
+    fn run(&self) {
+        let f = self.f.lock().unwrap().take();
+        if let Some(f) = f {
+            f();
+        }
+    }
+}
+
+impl Abort for BlockingTask {
+    fn abort(self: Arc<Self>) {
+        // NOTE: dropped outside of the lock, it completes the `JoinHandle`
+        let f = self.f.lock().unwrap().take();
+        drop(f);
+    }
+}
+
+struct State {
+    queue: VecDeque<Arc<BlockingTask>>,
+    threads: usize,
+
+    /// Threads waiting for a closure, but not notified yet
+    idle: usize,
+
+    /// Notifications sent to idle threads, and not received yet
+    notified: usize,
+
+    max_threads: usize,
+    idle_timeout: Duration,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+
+    /// Signalled when a closure is queued, or the pool shuts down
+    condvar: Condvar,
+
+    /// Whether the closures run right away on the spawning thread,
+    /// see `BlockingPool::new_inline`
+    inline: bool,
+}
+
+impl Shared {
+    fn run(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.shutdown {
+                break;
+            }
+            if let Some(task) = state.queue.pop_front() {
+                drop(state);
+                task.run();
+                state = self.state.lock().unwrap();
+                continue;
+            }
+
+            state.idle += 1;
+            loop {
+                let idle_timeout = state.idle_timeout;
+                let (guard, wait) = self.condvar.wait_timeout(state, idle_timeout).unwrap();
+                state = guard;
+                if state.notified > 0 {
+                    // NOTE: the spawner counted this thread out of the idle ones already
+                    state.notified -= 1;
+                    break;
+                }
+                if state.shutdown || wait.timed_out() {
+                    state.idle -= 1;
+                    state.threads -= 1;
+                    return;
+                }
+            }
+        }
+        state.threads -= 1;
+    }
+}
+
+/// Handle to spawn blocking closures in a `BlockingPool`
+#[derive(Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+thread_local! {
+    /// Blocking pool of the executor running on the current thread
+    static CURRENT: RefCell<Option<Handle>> = const { RefCell::new(None) };
+}
+
+impl Handle {
+    /// Returns the handle of the blocking pool entered on the current thread,
+    /// or the one of a process-wide pool if there is none.
+    pub fn current() -> Self {
+        static DEFAULT: OnceLock<BlockingPool> = OnceLock::new();
+
+        CURRENT
+            .with(|current| current.borrow().clone())
+            .unwrap_or_else(|| DEFAULT.get_or_init(BlockingPool::new).handle())
+    }
+
+    /// Runs the blocking `f` on a thread of the pool, see `spawn_blocking`.
+    pub fn spawn_blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce() -> T + Send + 'static,
+    ) -> JoinHandle<T> {
+        let (f, join_state) = join_closure(f);
+        let task = Arc::new(BlockingTask {
+            f: Mutex::new(Some(Box::new(f))),
+        });
+        let handle = JoinHandle::new(join_state, Arc::downgrade(&task) as Weak<dyn Abort>);
+
+        if self.shared.inline {
+            task.run();
+            return handle;
+        }
+
+        let mut state = self.shared.state.lock().unwrap();
+        if state.shutdown {
+            // Dropping the closure cancels the `JoinHandle`
+            return handle;
+        }
+        state.queue.push_back(task);
+        if state.idle > 0 {
+            state.idle -= 1;
+            state.notified += 1;
+            self.shared.condvar.notify_one();
+        } else if state.threads < state.max_threads {
+            // NOTE: otherwise the closure waits for a thread to be done
+            state.threads += 1;
+            let shared = self.shared.clone();
+            thread::Builder::new()
+                .name("blocking".into())
+                .spawn(move || {
+                    local_executor::allow_local_wakeups();
+                    shared.run()
+                })
+                .expect("failed to spawn a blocking thread");
+        }
+
+        handle
+    }
+}
+
+/// A pool of threads running blocking closures.
+///
+/// Threads are started on demand, up to a maximum, and stop after
+/// being idle for a while. Dropping the pool cancels the closures
+/// not started yet, the running ones are left to complete.
+pub struct BlockingPool {
+    handle: Handle,
+}
+
+impl BlockingPool {
+    pub fn new() -> Self {
+        BlockingPool::build(false)
+    }
+
+    /// Creates a pool without threads, running the closures right away on the thread
+    /// spawning them, so a `Simulation` stays deterministic.
+    pub(crate) fn new_inline() -> Self {
+        BlockingPool::build(true)
+    }
+
+    fn build(inline: bool) -> Self {
+        BlockingPool {
+            handle: Handle {
+                shared: Arc::new(Shared {
+                    state: Mutex::new(State {
+                        queue: VecDeque::new(),
+                        threads: 0,
+                        idle: 0,
+                        notified: 0,
+                        max_threads: MAX_THREADS,
+                        idle_timeout: IDLE_TIMEOUT,
+                        shutdown: false,
+                    }),
+                    condvar: Condvar::new(),
+                    inline,
+                }),
+            },
+        }
+    }
+
+    /// Sets the maximum number of threads, the closures spawned while they
+    /// are all busy are queued.
+    pub fn set_max_threads(&self, max_threads: usize) {
+        assert!(
+            max_threads > 0,
+            "the maximum number of threads must be non-zero"
+        );
+        self.handle.shared.state.lock().unwrap().max_threads = max_threads;
+    }
+
+    /// Sets how long a thread waits for a new closure before it stops.
+    pub fn set_idle_timeout(&self, idle_timeout: Duration) {
+        self.handle.shared.state.lock().unwrap().idle_timeout = idle_timeout;
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Makes `spawn_blocking` called on the current thread use this pool
+    /// until the returned guard is dropped.
+    pub fn enter(&self) -> EnterGuard {
+        let previous = CURRENT.with(|current| current.replace(Some(self.handle())));
+        EnterGuard { previous }
+    }
+}
+
+impl Default for BlockingPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for BlockingPool {
+    fn drop(&mut self) {
+        let mut state = self.handle.shared.state.lock().unwrap();
+        state.shutdown = true;
+        let queue = std::mem::take(&mut state.queue);
+        drop(state);
+        self.handle.shared.condvar.notify_all();
+
+        // Cancels their `JoinHandle`s
+        drop(queue);
+    }
+}
+
+/// Restores the previously entered blocking pool on drop
+pub struct EnterGuard {
+    previous: Option<Handle>,
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::new_executor_and_spawner;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn runs_the_closures_on_a_bounded_number_of_threads() {
+        let (executor, spawner) = new_executor_and_spawner();
+        executor.blocking_pool().set_max_threads(2);
+        executor
+            .blocking_pool()
+            .set_idle_timeout(Duration::from_millis(50));
+        let running = Arc::new(AtomicUsize::new(0));
+
+        let outputs = spawner
+            .spawn(async move {
+                let handles = (0..4)
+                    .map(|i| {
+                        let running = running.clone();
+                        spawn_blocking(move || {
+                            assert!(running.fetch_add(1, Ordering::SeqCst) < 2);
+                            thread::sleep(Duration::from_millis(20));
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            i * 10
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let panicked = spawn_blocking(|| panic!("boom")).await;
+
+                let mut outputs = Vec::new();
+                for handle in handles {
+                    outputs.push(handle.await.unwrap());
+                }
+                (outputs, panicked.unwrap_err().is_panic())
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        let outputs = futures::executor::block_on(outputs).unwrap();
+        assert_eq!(outputs, (vec![0, 10, 20, 30], true));
+
+        // The idle threads stop
+        thread::sleep(Duration::from_millis(200));
+        let state = executor.blocking_pool().handle.shared.state.lock().unwrap();
+        assert_eq!(state.threads, 0);
+    }
+}
@@ -0,0 +1,28 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: channels between the tasks of our executors, on the pattern of the `SharedState`
+//       of `TimerFuture`: a state behind a mutex, with the wakers of the tasks waiting
+//       on it. Receiving consumes the budget of the task (see `coop`), so a task
+//       draining a busy channel still yields to the others
+
+use std::task::Waker;
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+pub mod watch;
+
+/// Adds the `waker` of a task waiting on a channel, unless it's there already
+fn register(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+/// Wakes the tasks taken out of a channel state
+///
+/// NOTE: called once the lock of the state is released, so the tasks
+///       can't contend on it while being scheduled
+fn wake_all(wakers: Vec<Waker>) {
+    wakers.into_iter().for_each(Waker::wake);
+}
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: a channel from many senders to many receivers, each receiver gets a clone
+//       of every value. Sending never waits: the channel keeps the last `capacity`
+//       values only, and a receiver too slow to keep up is told how many it missed
+
+use {
+    super::{register, wake_all},
+    crate::coop,
+    std::{
+        collections::VecDeque,
+        convert::TryFrom,
+        fmt, mem,
+        sync::{Arc, Mutex},
+        task::{ready, Context, Poll, Waker},
+    },
+};
+
+/// Creates a channel keeping the last `capacity` values for the receivers.
+///
+/// Panics if the `capacity` is zero.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "the capacity must be non-zero");
+
+    let shared = Arc::new(Mutex::new(State {
+        buffer: VecDeque::with_capacity(capacity),
+        head: 0,
+        capacity,
+        senders: 1,
+        receivers: 1,
+        wakers: Vec::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, next: 0 },
+    )
+}
+
+struct State<T> {
+    /// The last values sent, the oldest first
+    buffer: VecDeque<T>,
+
+    /// Position of the oldest value of the `buffer`, counted since the first value sent
+    head: u64,
+
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+
+    /// Of the receivers waiting for a value
+    wakers: Vec<Waker>,
+}
+
+impl<T> State<T> {
+    /// Position of the next value to be sent
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+
+    /// Position of the next value to receive
+    next: u64,
+}
+
+/// There is no `Receiver` left, the value is given back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("there is no receiver left")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every `Sender` was dropped, and the receiver got all the values left
+    Closed,
+
+    /// The receiver fell behind, and missed that many values.
+    /// The next `recv` returns the oldest value still kept.
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Closed => f.write_str("every sender was dropped"),
+            RecvError::Lagged(missed) => write!(f, "the receiver lagged behind by {}", missed),
+        }
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+impl<T> Sender<T> {
+    /// Sends the `value` to every `Receiver`, dropping the oldest value
+    /// if the channel is full.
+    ///
+    /// Returns the number of receivers, or the value back if there is none.
+    pub fn send(&self, value: T) -> Result<usize, SendError<T>> {
+        let mut state = self.shared.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        state.buffer.push_back(value);
+        let mut oldest = None;
+        if state.buffer.len() > state.capacity {
+            state.head += 1;
+            oldest = state.buffer.pop_front();
+        }
+        let receivers = state.receivers;
+        let wakers = mem::take(&mut state.wakers);
+        drop(state);
+
+        drop(oldest);
+        wake_all(wakers);
+        Ok(receivers)
+    }
+
+    /// Creates a `Receiver` getting the values sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.lock().unwrap();
+        state.receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            next: state.tail(),
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().unwrap().receivers
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.senders -= 1;
+        let wakers = match state.senders {
+            0 => mem::take(&mut state.wakers),
+            _ => Vec::new(),
+        };
+        drop(state);
+
+        wake_all(wakers);
+    }
+}
+
+impl<T: Clone> Receiver<T> {
+    /// Receives the next value.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, RecvError>> {
+        ready!(coop::poll_proceed(cx));
+
+        let mut state = self.shared.lock().unwrap();
+        if self.next < state.head {
+            let missed = state.head - self.next;
+            self.next = state.head;
+            return Poll::Ready(Err(RecvError::Lagged(missed)));
+        }
+        if self.next < state.tail() {
+            let index = usize::try_from(self.next - state.head).unwrap();
+            self.next += 1;
+            return Poll::Ready(Ok(state.buffer[index].clone()));
+        }
+        if state.senders == 0 {
+            return Poll::Ready(Err(RecvError::Closed));
+        }
+        register(&mut state.wakers, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().receivers -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::new_executor_and_spawner;
+
+    #[test]
+    fn every_receiver_gets_the_values_and_slow_ones_lag() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (sender, mut fast) = channel(2);
+        let mut slow = sender.subscribe();
+
+        let received = spawner
+            .spawn(async move {
+                let mut received = Vec::new();
+                while let Ok(value) = fast.recv().await {
+                    received.push(value);
+                }
+                received
+            })
+            .unwrap();
+        spawner
+            .spawn(async move {
+                for value in 0..5 {
+                    assert_eq!(sender.send(value), Ok(2));
+                    // Let the fast receiver keep up
+                    crate::coop::yield_now().await;
+                }
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(
+            futures::executor::block_on(received).unwrap(),
+            [0, 1, 2, 3, 4]
+        );
+        futures::executor::block_on(async {
+            assert_eq!(slow.recv().await, Err(RecvError::Lagged(3)));
+            assert_eq!(slow.recv().await, Ok(3));
+            assert_eq!(slow.recv().await, Ok(4));
+            assert_eq!(slow.recv().await, Err(RecvError::Closed));
+        });
+    }
+}
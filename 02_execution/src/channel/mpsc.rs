@@ -0,0 +1,275 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: a bounded channel from many senders to a single receiver. A full channel
+//       makes `send` wait for a free slot, so a slow receiver pushes back on the senders
+
+use {
+    super::{register, wake_all},
+    crate::coop,
+    futures::stream::Stream,
+    std::{
+        collections::VecDeque,
+        fmt, mem,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{ready, Context, Poll, Waker},
+    },
+};
+
+/// Creates a channel buffering up to `capacity` values.
+///
+/// Panics if the `capacity` is zero.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "the capacity must be non-zero");
+
+    let shared = Arc::new(Mutex::new(State {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receiver_dropped: false,
+        receiver_waker: None,
+        sender_wakers: Vec::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_dropped: bool,
+    receiver_waker: Option<Waker>,
+
+    /// Of the tasks waiting for a free slot.
+    ///
+    /// NOTE: they are all woken up when a slot is freed, and race for it. Waking only
+    ///       the first one would lose the slot if its `send` future is dropped meanwhile
+    sender_wakers: Vec<Waker>,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+/// The `Receiver` was dropped, the value is given back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the receiver was dropped")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is full
+    Full(T),
+
+    /// The `Receiver` was dropped
+    Closed(T),
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => f.write_str("the channel is full"),
+            TrySendError::Closed(_) => f.write_str("the receiver was dropped"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for TrySendError<T> {}
+
+impl<T> Sender<T> {
+    /// Sends the `value`, waiting for a free slot if the channel is full.
+    ///
+    /// Returns the value back if the `Receiver` was dropped.
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut value = Some(value);
+        futures::future::poll_fn(|cx| {
+            ready!(coop::poll_proceed(cx));
+
+            match self.try_send(value.take().unwrap()) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(TrySendError::Closed(rejected)) => Poll::Ready(Err(SendError(rejected))),
+                Err(TrySendError::Full(rejected)) => {
+                    let mut state = self.shared.lock().unwrap();
+                    // NOTE: checked again, a slot may have been freed since `try_send`
+                    if state.queue.len() < state.capacity || state.receiver_dropped {
+                        cx.waker().wake_by_ref();
+                    } else {
+                        register(&mut state.sender_wakers, cx.waker());
+                    }
+                    value = Some(rejected);
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+
+    /// Sends the `value` if there is a free slot.
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let mut state = self.shared.lock().unwrap();
+        if state.receiver_dropped {
+            return Err(TrySendError::Closed(value));
+        }
+        if state.queue.len() == state.capacity {
+            return Err(TrySendError::Full(value));
+        }
+        state.queue.push_back(value);
+        let waker = state.receiver_waker.take();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Whether the `Receiver` was dropped
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().receiver_dropped
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.senders -= 1;
+        let waker = match state.senders {
+            0 => state.receiver_waker.take(),
+            _ => None,
+        };
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Receives the next value, or `None` once every `Sender` is dropped
+    /// and the channel is empty.
+    pub async fn recv(&mut self) -> Option<T> {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        ready!(coop::poll_proceed(cx));
+
+        let mut state = self.shared.lock().unwrap();
+        match state.queue.pop_front() {
+            Some(value) => {
+                let wakers = mem::take(&mut state.sender_wakers);
+                drop(state);
+
+                wake_all(wakers);
+                Poll::Ready(Some(value))
+            }
+            None if state.senders == 0 => Poll::Ready(None),
+            None => {
+                state.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.receiver_dropped = true;
+        // The values left are dropped outside of the lock
+        let queue = mem::take(&mut state.queue);
+        let wakers = mem::take(&mut state.sender_wakers);
+        drop(state);
+
+        drop(queue);
+        wake_all(wakers);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::new_executor_and_spawner;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn a_full_channel_makes_the_senders_wait() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (sender, mut receiver) = channel(2);
+        let sent = Arc::new(AtomicUsize::new(0));
+
+        for id in 0..3 {
+            let sender = sender.clone();
+            let sent = sent.clone();
+            spawner
+                .spawn(async move {
+                    for i in 0..10 {
+                        sender.send(id * 10 + i).await.unwrap();
+                        sent.fetch_add(1, Ordering::SeqCst);
+                    }
+                })
+                .unwrap();
+        }
+        drop(sender);
+
+        let received = spawner
+            .spawn(async move {
+                // Let the senders fill the channel up
+                crate::coop::yield_now().await;
+                let mut received = Vec::new();
+                while let Some(value) = receiver.recv().await {
+                    // Never more than the capacity ahead of the receiver
+                    assert!(sent.load(Ordering::SeqCst) <= received.len() + 2 + 1);
+                    received.push(value);
+                }
+                received
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        let mut received = futures::executor::block_on(received).unwrap();
+        received.sort_unstable();
+        assert_eq!(received, (0..30).collect::<Vec<_>>());
+
+        let (sender, receiver) = channel(1);
+        sender.try_send(1).unwrap();
+        assert_eq!(sender.try_send(2), Err(TrySendError::Full(2)));
+        drop(receiver);
+        assert_eq!(sender.try_send(3), Err(TrySendError::Closed(3)));
+    }
+}
@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: a channel to send a single value, e.g. the response to a request
+
+use {
+    crate::coop,
+    std::{
+        fmt,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{ready, Context, Poll, Waker},
+    },
+};
+
+/// Creates a channel for a single value.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(State {
+        value: None,
+        sender_dropped: false,
+        receiver_dropped: false,
+        waker: None,
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+struct State<T> {
+    value: Option<T>,
+    sender_dropped: bool,
+    receiver_dropped: bool,
+
+    /// Of the task waiting on the `Receiver`
+    waker: Option<Waker>,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+/// A future completing with the sent value
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+/// The `Sender` was dropped without sending a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the sender was dropped without sending a value")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The value isn't sent yet
+    Empty,
+
+    /// The `Sender` was dropped without sending a value
+    Closed,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryRecvError::Empty => f.write_str("the value isn't sent yet"),
+            TryRecvError::Closed => f.write_str("the sender was dropped without sending a value"),
+        }
+    }
+}
+
+impl std::error::Error for TryRecvError {}
+
+impl<T> Sender<T> {
+    /// Sends the `value`, or gives it back if the `Receiver` was dropped.
+    pub fn send(self, value: T) -> Result<(), T> {
+        let mut state = self.shared.lock().unwrap();
+        if state.receiver_dropped {
+            return Err(value);
+        }
+        state.value = Some(value);
+        let waker = state.waker.take();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Whether the `Receiver` was dropped, so nobody waits for the value anymore
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().receiver_dropped
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.sender_dropped = true;
+        let waker = state.waker.take();
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        let mut state = self.shared.lock().unwrap();
+        match state.value.take() {
+            Some(value) => Ok(value),
+            None if state.sender_dropped => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(coop::poll_proceed(cx));
+
+        let mut state = self.shared.lock().unwrap();
+        match state.value.take() {
+            Some(value) => Poll::Ready(Ok(value)),
+            None if state.sender_dropped => Poll::Ready(Err(RecvError)),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.receiver_dropped = true;
+        // The value, if any, is dropped outside of the lock
+        let value = state.value.take();
+        drop(state);
+        drop(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::new_executor_and_spawner, timer_future::TimerFuture};
+    use std::time::Duration;
+
+    #[test]
+    fn the_receiver_gets_the_value_or_an_error_if_none_was_sent() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (sender, receiver) = channel();
+        let (dropped_sender, dropped_receiver) = channel::<u32>();
+
+        spawner
+            .spawn(async move {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                sender.send(42).unwrap();
+                drop(dropped_sender);
+            })
+            .unwrap();
+        let received = spawner
+            .spawn(async move { (receiver.await, dropped_receiver.await) })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        let received = futures::executor::block_on(received).unwrap();
+        assert_eq!(received, (Ok(42), Err(RecvError)));
+
+        let (sender, receiver) = channel();
+        drop(receiver);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(1), Err(1));
+    }
+}
@@ -0,0 +1,240 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: a channel holding a single value, e.g. a configuration: the receivers see
+//       the latest value only, and can wait for it to change
+
+use {
+    super::{register, wake_all},
+    crate::coop,
+    std::{
+        fmt, mem,
+        ops::Deref,
+        sync::{Arc, Mutex, MutexGuard},
+        task::{ready, Context, Poll, Waker},
+    },
+};
+
+/// Creates a channel holding the `initial` value.
+pub fn channel<T>(initial: T) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Mutex::new(State {
+        value: initial,
+        version: 0,
+        sender_dropped: false,
+        receivers: 1,
+        wakers: Vec::new(),
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared, seen: 0 },
+    )
+}
+
+struct State<T> {
+    value: T,
+
+    /// Incremented on each change of the value
+    version: u64,
+
+    sender_dropped: bool,
+    receivers: usize,
+
+    /// Of the receivers waiting for a change
+    wakers: Vec<Waker>,
+}
+
+pub struct Sender<T> {
+    shared: Arc<Mutex<State<T>>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Mutex<State<T>>>,
+
+    /// Version of the last value seen
+    seen: u64,
+}
+
+/// A borrow of the value of the channel, the senders are blocked while it lives
+pub struct Ref<'a, T> {
+    state: MutexGuard<'a, State<T>>,
+}
+
+impl<T> Deref for Ref<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.state.value
+    }
+}
+
+/// There is no `Receiver` left, the value is given back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("there is no receiver left")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+
+/// The `Sender` was dropped, so the value won't change anymore
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the sender was dropped")
+    }
+}
+
+impl std::error::Error for RecvError {}
+
+impl<T> Sender<T> {
+    /// Replaces the value, and notifies the receivers.
+    ///
+    /// Returns the value back if there is no `Receiver` left.
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let mut state = self.shared.lock().unwrap();
+        if state.receivers == 0 {
+            return Err(SendError(value));
+        }
+        let previous = mem::replace(&mut state.value, value);
+        state.version += 1;
+        let wakers = mem::take(&mut state.wakers);
+        drop(state);
+
+        drop(previous);
+        wake_all(wakers);
+        Ok(())
+    }
+
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            state: self.shared.lock().unwrap(),
+        }
+    }
+
+    /// Creates a `Receiver` which has seen the current value already.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut state = self.shared.lock().unwrap();
+        state.receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            seen: state.version,
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock().unwrap();
+        state.sender_dropped = true;
+        let wakers = mem::take(&mut state.wakers);
+        drop(state);
+
+        wake_all(wakers);
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Borrows the latest value, without marking it as seen.
+    pub fn borrow(&self) -> Ref<'_, T> {
+        Ref {
+            state: self.shared.lock().unwrap(),
+        }
+    }
+
+    /// Borrows the latest value, and marks it as seen.
+    pub fn borrow_and_update(&mut self) -> Ref<'_, T> {
+        let state = self.shared.lock().unwrap();
+        self.seen = state.version;
+        Ref { state }
+    }
+
+    /// Whether the value changed since it was last seen
+    pub fn has_changed(&self) -> bool {
+        self.shared.lock().unwrap().version != self.seen
+    }
+
+    /// Waits for a value not seen yet, and marks it as seen.
+    ///
+    /// Returns an error once the `Sender` is dropped.
+    pub async fn changed(&mut self) -> Result<(), RecvError> {
+        futures::future::poll_fn(|cx| self.poll_changed(cx)).await
+    }
+
+    fn poll_changed(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), RecvError>> {
+        ready!(coop::poll_proceed(cx));
+
+        let mut state = self.shared.lock().unwrap();
+        if state.version != self.seen {
+            self.seen = state.version;
+            return Poll::Ready(Ok(()));
+        }
+        if state.sender_dropped {
+            return Poll::Ready(Err(RecvError));
+        }
+        register(&mut state.wakers, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            seen: self.seen,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.lock().unwrap().receivers -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::new_executor_and_spawner, timer_future::TimerFuture};
+    use std::time::Duration;
+
+    #[test]
+    fn receivers_wait_for_the_latest_value() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (sender, mut receiver) = channel("initial");
+        assert_eq!(*receiver.borrow(), "initial");
+        assert!(!receiver.has_changed());
+
+        let seen = spawner
+            .spawn(async move {
+                let mut seen = Vec::new();
+                while receiver.changed().await.is_ok() {
+                    seen.push(*receiver.borrow_and_update());
+                }
+                seen
+            })
+            .unwrap();
+        spawner
+            .spawn(async move {
+                // Changed twice before the receiver could see the first value
+                sender.send("first").unwrap();
+                sender.send("second").unwrap();
+                TimerFuture::new(Duration::from_millis(10)).await;
+                sender.send("third").unwrap();
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(
+            futures::executor::block_on(seen).unwrap(),
+            ["second", "third"]
+        );
+    }
+}
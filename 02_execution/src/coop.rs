@@ -0,0 +1,119 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: cooperative scheduling. A future that is always ready (e.g. draining a full
+//       channel) never returns `Poll::Pending` by itself, so it would keep the executor
+//       from polling the other tasks. Every poll of a task gets a budget instead,
+//       consumed by the leaf futures (timers, I/O, join handles, ...): once it's
+//       exhausted, they return `Poll::Pending` and wake the task, so it yields
+
+use std::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Number of operations a task can make per poll
+const BUDGET: u8 = 128;
+
+thread_local! {
+    /// Operations left for the task being polled on this thread,
+    /// `None` outside of our executors, where the budget is unconstrained
+    static CURRENT: Cell<Option<u8>> = const { Cell::new(None) };
+}
+
+/// Runs `f` (the poll of a task) with a fresh budget.
+pub(crate) fn budget<R>(f: impl FnOnce() -> R) -> R {
+    /// Restores the budget of the outer poll on drop, even if `f` panics
+    struct ResetGuard(Option<u8>);
+
+    impl Drop for ResetGuard {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.set(self.0));
+        }
+    }
+
+    let _guard = ResetGuard(CURRENT.with(|current| current.replace(Some(BUDGET))));
+    f()
+}
+
+/// Consumes a unit of the budget of the current task, or wakes the task
+/// and returns `Poll::Pending` if it's exhausted.
+///
+/// Called by the leaf futures before making progress.
+///
+/// NOTE: the unit is consumed even if the operation turns out to be pending
+pub(crate) fn poll_proceed(cx: &mut Context<'_>) -> Poll<()> {
+    CURRENT.with(|current| match current.get() {
+        Some(0) => {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+        Some(budget) => {
+            current.set(Some(budget - 1));
+            Poll::Ready(())
+        }
+        None => Poll::Ready(()),
+    })
+}
+
+/// Yields to the executor once, so it can run the other tasks
+/// before the current one resumes.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by `yield_now`
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::new_executor_and_spawner;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn always_ready_tasks_yield_once_the_budget_is_exhausted() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        for _ in 0..2 {
+            let stop = stop.clone();
+            spawner
+                .spawn(async move {
+                    // Never pending by itself, so it would never let the other task run
+                    while !stop.load(Ordering::SeqCst) {
+                        futures::future::poll_fn(poll_proceed).await;
+                    }
+                })
+                .unwrap();
+        }
+        spawner
+            .spawn(async move {
+                yield_now().await;
+                stop.store(true, Ordering::SeqCst);
+            })
+            .unwrap();
+        drop(spawner);
+
+        executor.run();
+    }
+}
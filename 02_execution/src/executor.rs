@@ -0,0 +1,1137 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// https://rust-lang.github.io/async-book/02_execution/04_executor.html
+// 2.3. Applied: Build an Executor
+
+use {
+    // The timer we wrote in the previous section:
+    // timer_future::TimerFuture,
+    // NOTE: ^ the executor only needs the driver that fires those timers
+    crate::{
+        blocking::BlockingPool,
+        coop,
+        join_handle::{join_future, Abort, JoinHandle, ReportPanic},
+        metrics::{Metrics, MetricsSnapshot},
+        reactor::Reactor,
+        timer_future::TimerDriver,
+    },
+    crossbeam_queue::SegQueue,
+    futures::task::{waker_ref, ArcWake},
+    std::{
+        any::Any,
+        cell::UnsafeCell,
+        fmt,
+        future::Future,
+        panic::{self, AssertUnwindSafe, Location},
+        pin::Pin,
+        sync::atomic::{self, AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        sync::{Arc, Mutex, Weak},
+        task::Context,
+        time::{Duration, Instant},
+    },
+};
+
+/// Task executor that receives tasks off of a queue and runs them.
+pub struct Executor {
+    ready_queue: Arc<ReadyQueue>,
+
+    registry: Arc<TaskRegistry>,
+
+    /// Fires the `TimerFuture`s created by the tasks of this executor.
+    timer: TimerDriver,
+
+    /// Runs the closures given to `spawn_blocking` by the tasks of this executor.
+    blocking: BlockingPool,
+
+    hooks: Hooks,
+}
+
+/// Called with the payload of every panic caught while polling a task,
+/// before the payload is handed over to the `JoinHandle` of that task.
+pub type PanicHook = Arc<dyn Fn(&(dyn Any + Send)) + Send + Sync>;
+
+/// Called with every poll that took longer than the slow poll threshold.
+pub type SlowPollHook = Arc<dyn Fn(&SlowPoll) + Send + Sync>;
+
+/// A poll that took longer than the threshold set with
+/// `Executor::set_slow_poll_threshold`, most likely because of a blocking call
+#[derive(Debug, Clone, Copy)]
+pub struct SlowPoll {
+    /// Id of the task, in the order the tasks were spawned
+    pub task_id: u64,
+
+    /// Where the task was spawned
+    pub location: &'static Location<'static>,
+
+    pub duration: Duration,
+}
+
+impl fmt::Display for SlowPoll {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "task {} spawned at {} took {:?} to poll, is it blocking?",
+            self.task_id, self.location, self.duration
+        )
+    }
+}
+
+/// Hooks called while running the tasks of an executor
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub(crate) panic: Option<PanicHook>,
+
+    pub(crate) slow_poll_threshold: Option<Duration>,
+
+    /// Reports the slow polls with `eprintln!` if not set
+    pub(crate) slow_poll: Option<SlowPollHook>,
+}
+
+impl Hooks {
+    fn check_poll_duration(
+        &self,
+        task_id: u64,
+        location: &'static Location<'static>,
+        duration: Duration,
+    ) {
+        if self
+            .slow_poll_threshold
+            .is_some_and(|threshold| duration > threshold)
+        {
+            let slow_poll = SlowPoll {
+                task_id,
+                location,
+                duration,
+            };
+            match &self.slow_poll {
+                Some(hook) => hook(&slow_poll),
+                None => eprintln!("warning: {}", slow_poll),
+            }
+        }
+    }
+}
+
+/// `Spawner` spawns new futures onto the task queue
+/// (or the run queues of a `ThreadPool`).
+#[derive(Clone)]
+pub struct Spawner {
+    scheduler: Arc<dyn Schedule>,
+    registry: Arc<TaskRegistry>,
+}
+
+impl Spawner {
+    pub(crate) fn new(scheduler: Arc<dyn Schedule>, registry: Arc<TaskRegistry>) -> Self {
+        Spawner {
+            scheduler,
+            registry,
+        }
+    }
+}
+
+/// The reason a future couldn't be spawned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnError {
+    /// The executor already runs as many tasks as it's allowed to
+    AtCapacity,
+
+    /// The executor is shutting down, see `ShutdownHandle::shutdown`
+    Shutdown,
+}
+
+impl fmt::Display for SpawnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpawnError::AtCapacity => f.write_str("too many tasks spawned"),
+            SpawnError::Shutdown => f.write_str("the executor is shut down"),
+        }
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+/// Tasks spawned on an executor, shared by its spawners and its tasks
+pub(crate) struct TaskRegistry {
+    /// Number of tasks alive (not complete yet), to push back
+    /// on spawning once the maximum is reached
+    live: AtomicUsize,
+    max: AtomicUsize,
+
+    /// Set on shutdown, so no task can be spawned anymore
+    closed: AtomicBool,
+
+    /// Every spawned task, to cancel the remaining ones on shutdown
+    ///
+    /// NOTE: the dropped tasks are only swept out once they outnumber the live ones
+    tasks: Mutex<Vec<Weak<dyn Runnable>>>,
+
+    /// Recorded by the spawners and the tasks, see `Executor::metrics`
+    metrics: Metrics,
+
+    /// Id of the next task spawned
+    next_id: AtomicU64,
+}
+
+impl TaskRegistry {
+    pub(crate) fn new() -> Self {
+        TaskRegistry {
+            live: AtomicUsize::new(0),
+            max: AtomicUsize::new(usize::MAX),
+            closed: AtomicBool::new(false),
+            tasks: Mutex::new(Vec::new()),
+            metrics: Metrics::new(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn metrics(&self) -> MetricsSnapshot {
+        self.metrics.snapshot(self.live.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn set_max(&self, max_tasks: usize) {
+        self.max.store(max_tasks, Ordering::Relaxed);
+    }
+
+    fn increment(&self) -> Result<(), SpawnError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(SpawnError::Shutdown);
+        }
+        if self.live.fetch_add(1, Ordering::Relaxed) >= self.max.load(Ordering::Relaxed) {
+            self.decrement();
+            return Err(SpawnError::AtCapacity);
+        }
+        Ok(())
+    }
+
+    fn decrement(&self) {
+        self.live.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn register(&self, task: Weak<dyn Runnable>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.len() > 2 * self.live.load(Ordering::Relaxed) + 64 {
+            tasks.retain(|task| task.strong_count() > 0);
+        }
+        tasks.push(task);
+    }
+
+    /// Cancels every task that isn't complete yet, and returns them.
+    pub(crate) fn cancel_all(&self) -> Vec<CancelledTask> {
+        let tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+        tasks
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|task| task.clone().cancel())
+            .map(|task| CancelledTask {
+                task_id: task.id(),
+                location: task.location(),
+            })
+            .collect()
+    }
+}
+
+/// Places tasks onto the queue of the executor that polls them.
+///
+/// NOTE: scheduling never fails, the queues are unbounded
+pub(crate) trait Schedule: Send + Sync {
+    fn schedule(&self, task: TaskRef);
+}
+
+/// Scheduling class of a task, see `Spawner::spawn_with_priority`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    /// Latency-sensitive tasks, e.g. request handlers
+    High,
+
+    #[default]
+    Normal,
+
+    /// Batch jobs, which only get the time left over by the other classes
+    /// (and a minimal share of it, so they aren't starved)
+    Background,
+}
+
+/// The class of the run queue polled at each step of a round, so every class
+/// gets a share of the polls while the others are busy: 4/7 for `High`,
+/// 2/7 for `Normal` and 1/7 for `Background`. An empty class gives its turn
+/// to the highest class with a task ready.
+const ROUND: [Priority; 7] = [
+    Priority::High,
+    Priority::High,
+    Priority::High,
+    Priority::High,
+    Priority::Normal,
+    Priority::Normal,
+    Priority::Background,
+];
+
+/// Tasks ready to be polled by an `Executor`
+struct ReadyQueue {
+    /// A run queue per `Priority`
+    ///
+    /// NOTE: unbounded lock-free queues, so waking a task never blocks nor fails
+    tasks: [SegQueue<TaskRef>; 3],
+
+    /// Position in the `ROUND`, only used by the executor
+    step: AtomicUsize,
+
+    /// Set once every `Spawner` and `Task` is dropped, so no task can be queued anymore
+    closed: AtomicBool,
+
+    /// Whether the executor waits for a task in `Reactor::turn`
+    sleeping: AtomicBool,
+
+    /// Polled by the executor while the queue is empty,
+    /// and unparked to run a newly queued task
+    reactor: Reactor,
+
+    /// The deadline of the shutdown, unless it waits for the tasks indefinitely
+    deadline: Mutex<Option<Instant>>,
+
+    /// Whether the shutdown is requested, to check `deadline` only then
+    shutting_down: AtomicBool,
+}
+
+/// Schedules woken tasks onto the `ReadyQueue`.
+///
+/// Owned by the `Spawner`s and the `Task`s, so the queue is closed
+/// when the last of them is dropped.
+struct Scheduler {
+    ready_queue: Arc<ReadyQueue>,
+}
+
+impl Schedule for Scheduler {
+    fn schedule(&self, task: TaskRef) {
+        self.ready_queue.push(task);
+
+        // NOTE: `sleeping` is set before the executor checks the queue
+        //       for the last time, so either the executor sees the task,
+        //       or we see it sleeping and wake it up. An unpark sent before
+        //       the executor polls the reactor makes that poll return at once
+        atomic::fence(Ordering::SeqCst);
+        if self.ready_queue.sleeping.load(Ordering::SeqCst) {
+            self.ready_queue.reactor.handle().unpark();
+        }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.ready_queue.closed.store(true, Ordering::SeqCst);
+        self.ready_queue.reactor.handle().unpark();
+    }
+}
+
+impl ReadyQueue {
+    fn push(&self, task: TaskRef) {
+        self.tasks[task.priority() as usize].push(task);
+    }
+
+    /// Takes a task from the run queue whose turn it is, see `ROUND`
+    fn try_pop(&self) -> Option<TaskRef> {
+        let step = self.step.fetch_add(1, Ordering::Relaxed) % ROUND.len();
+        self.tasks[ROUND[step] as usize]
+            .pop()
+            .or_else(|| self.tasks.iter().find_map(SegQueue::pop))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.tasks.iter().all(SegQueue::is_empty)
+    }
+
+    /// Waits for the next task to run, or returns `None` once the queue is closed.
+    ///
+    /// During a shutdown, it also returns `None` once every task is complete,
+    /// or once the deadline of the shutdown is reached.
+    fn pop(&self, registry: &TaskRegistry) -> Option<TaskRef> {
+        loop {
+            if self.shutting_down.load(Ordering::Acquire)
+                && self
+                    .deadline
+                    .lock()
+                    .unwrap()
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return None;
+            }
+            if let Some(task) = self.try_pop() {
+                return Some(task);
+            }
+
+            self.sleeping.store(true, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+            if self.is_empty() {
+                if self.closed.load(Ordering::SeqCst) {
+                    self.sleeping.store(false, Ordering::SeqCst);
+                    return None;
+                }
+                // NOTE: the tasks only complete on this thread, so there is
+                //       nothing to wait for once none of them is live
+                if self.shutting_down.load(Ordering::Acquire)
+                    && registry.live.load(Ordering::Relaxed) == 0
+                {
+                    self.sleeping.store(false, Ordering::SeqCst);
+                    return None;
+                }
+                // Wait for I/O events, to be unparked by a queued task,
+                // or for the deadline of the shutdown
+                let deadline = *self.deadline.lock().unwrap();
+                let timeout =
+                    deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                self.reactor.turn(timeout);
+            }
+            self.sleeping.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Requests the shutdown of an `Executor`, from any thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    ready_queue: Arc<ReadyQueue>,
+    registry: Arc<TaskRegistry>,
+}
+
+/// What was left over by the shutdown of an `Executor`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ShutdownReport {
+    /// Tasks cancelled since they didn't complete in the grace period,
+    /// in the order they were spawned
+    pub cancelled: Vec<CancelledTask>,
+}
+
+/// A task cancelled by the shutdown of an `Executor`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CancelledTask {
+    /// Id of the task, in the order the tasks were spawned
+    pub task_id: u64,
+
+    /// Where the task was spawned
+    pub location: &'static Location<'static>,
+}
+
+impl ShutdownHandle {
+    /// Shuts the executor down: spawning fails with `SpawnError::Shutdown` from now on,
+    /// and `Executor::run` returns once every task is complete. The tasks still
+    /// running after the `grace_period` are cancelled, as if aborted.
+    /// A `Duration::MAX` grace period waits for every task to complete.
+    ///
+    /// NOTE: unlike the closing of the queue, it doesn't wait for every `Spawner`
+    ///       to be dropped, nor (with a grace period) for the tasks that are never woken
+    pub fn shutdown(&self, grace_period: Duration) {
+        self.registry.closed.store(true, Ordering::Release);
+
+        let mut deadline = self.ready_queue.deadline.lock().unwrap();
+        // NOTE: a grace period too long for an `Instant` has no deadline
+        if let Some(new_deadline) = Instant::now().checked_add(grace_period) {
+            if deadline.is_none_or(|deadline| new_deadline < deadline) {
+                *deadline = Some(new_deadline);
+            }
+        }
+        self.ready_queue
+            .shutting_down
+            .store(true, Ordering::Release);
+        self.ready_queue.reactor.handle().unpark();
+    }
+}
+
+/// A future that can reschedule itself to be polled by an `Executor`.
+pub(crate) struct Task<F> {
+    /// In-progress future that should be pushed to completion,
+    /// `None` once it's complete or aborted.
+    ///
+    /// Stored inline rather than boxed, so spawning allocates the task only.
+    ///
+    /// SAFETY: only accessed by the thread running the task, see `Task::poll`
+    ///         (or dropping it). A task is only queued on the transitions to
+    ///         `Scheduled`, so it's in a single run queue at a time and popped
+    ///         by a single thread, which moves the `state` to `Running`.
+    ///         Wakeups meanwhile only move it to `Notified`, it's queued again
+    ///         once that thread is done with the future
+    future: UnsafeCell<Option<F>>,
+
+    /// Handle to place the task itself back onto the task queue.
+    scheduler: Arc<dyn Schedule>,
+
+    /// Scheduling state, a `State`
+    state: AtomicU8,
+
+    /// Set by `JoinHandle::abort`, the future is dropped instead of being polled.
+    aborted: AtomicBool,
+
+    /// Hands the payload over to the `JoinHandle`, if the future panics.
+    join_state: Arc<dyn ReportPanic + Send + Sync>,
+
+    registry: Arc<TaskRegistry>,
+
+    /// When the task was last queued, see `Metrics::now`
+    scheduled_at: AtomicU64,
+
+    /// Number of times the task was polled
+    polls: AtomicU64,
+
+    /// Id of the task, and where it was spawned, to report slow polls
+    id: u64,
+    location: &'static Location<'static>,
+
+    priority: Priority,
+}
+
+// SAFETY: the `future` is only accessed by the thread running the task,
+//         see `Task::poll`
+unsafe impl<F: Send> Sync for Task<F> {}
+
+/// A type-erased `Task`, as stored in the run queues
+pub(crate) type TaskRef = Arc<dyn Runnable>;
+
+pub(crate) trait Runnable: Send + Sync {
+    /// Polls the future of a scheduled task once.
+    fn run(self: Arc<Self>, hooks: &Hooks);
+
+    /// Aborts the task, unless it's complete already. Returns whether it wasn't.
+    fn cancel(self: Arc<Self>) -> bool;
+
+    fn priority(&self) -> Priority;
+
+    /// Id of the task, in the order the tasks were spawned
+    fn id(&self) -> u64;
+
+    /// Where the task was spawned
+    fn location(&self) -> &'static Location<'static>;
+}
+
+/// Scheduling state of a `Task`, so a task woken N times before being polled
+/// is queued (and polled) once, rather than N times
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum State {
+    /// Waiting for a wakeup
+    Idle,
+
+    /// Queued to be polled
+    Scheduled,
+
+    /// Being polled
+    Running,
+
+    /// Woken while being polled, to be queued again once the poll is over
+    Notified,
+
+    /// The future has completed, panicked or been aborted
+    Complete,
+}
+
+impl State {
+    fn load(state: &AtomicU8) -> Self {
+        match state.load(Ordering::Acquire) {
+            0 => State::Idle,
+            1 => State::Scheduled,
+            2 => State::Running,
+            3 => State::Notified,
+            _ => State::Complete,
+        }
+    }
+
+    /// Replaces the `current` state with the `new` one, unless another thread
+    /// has changed it in the meantime
+    fn transition(state: &AtomicU8, current: Self, new: Self) -> Result<(), Self> {
+        state
+            .compare_exchange(
+                current as u8,
+                new as u8,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .map(|_| ())
+            .map_err(|_| State::load(state))
+    }
+}
+
+impl<F> Drop for Task<F> {
+    fn drop(&mut self) {
+        // A task dropped before completion, since it's never woken anymore
+        if State::load(&self.state) != State::Complete {
+            self.registry.decrement();
+        }
+    }
+}
+
+pub fn new_executor_and_spawner() -> (Executor, Spawner) {
+    // NOTE: the course uses a `sync_channel` with at most 10 000 queued tasks here,
+    //       which panics on a burst of wakeups. Our queue is unbounded instead,
+    //       and the number of tasks can be limited with `Executor::set_max_tasks`
+    let ready_queue = Arc::new(ReadyQueue {
+        tasks: [SegQueue::new(), SegQueue::new(), SegQueue::new()],
+        step: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+        sleeping: AtomicBool::new(false),
+        reactor: Reactor::new(),
+        deadline: Mutex::new(None),
+        shutting_down: AtomicBool::new(false),
+    });
+    let registry = Arc::new(TaskRegistry::new());
+    let executor = Executor {
+        ready_queue: ready_queue.clone(),
+        registry: registry.clone(),
+        timer: TimerDriver::new(),
+        blocking: BlockingPool::new(),
+        hooks: Hooks::default(),
+    };
+    let spawner = Spawner::new(Arc::new(Scheduler { ready_queue }), registry);
+    (executor, spawner)
+}
+
+impl Spawner {
+    /// Spawns the `future` with the `Normal` priority.
+    #[track_caller]
+    pub fn spawn<T>(
+        &self,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> Result<JoinHandle<T>, SpawnError>
+    where
+        T: Send + 'static,
+    {
+        self.spawn_with_priority(Priority::Normal, future)
+    }
+
+    /// Spawns the `future` in the given scheduling class.
+    ///
+    /// Only the `Executor` has classes: the `priority` is ignored by a `ThreadPool`,
+    /// whose workers run every task alike in FIFO order (and by a `Simulation`,
+    /// which picks the tasks at random).
+    #[track_caller]
+    pub fn spawn_with_priority<T>(
+        &self,
+        priority: Priority,
+        future: impl Future<Output = T> + 'static + Send,
+    ) -> Result<JoinHandle<T>, SpawnError>
+    where
+        T: Send + 'static,
+    {
+        self.registry.increment()?;
+        self.registry.metrics.record_spawn();
+
+        let (future, join_state) = join_future(future);
+        let task = Arc::new(Task {
+            future: UnsafeCell::new(Some(future)),
+            scheduler: self.scheduler.clone(),
+            state: AtomicU8::new(State::Scheduled as u8),
+            aborted: AtomicBool::new(false),
+            join_state: join_state.clone(),
+            registry: self.registry.clone(),
+            scheduled_at: AtomicU64::new(self.registry.metrics.now()),
+            polls: AtomicU64::new(0),
+            id: self.registry.next_id.fetch_add(1, Ordering::Relaxed),
+            location: Location::caller(),
+            priority,
+        });
+        let join_handle = JoinHandle::new(join_state, Arc::downgrade(&task) as _);
+        self.registry.register(Arc::downgrade(&task) as _);
+        self.scheduler.schedule(task);
+        Ok(join_handle)
+    }
+}
+
+impl<F: Future<Output = ()> + Send + 'static> ArcWake for Task<F> {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        // Implement `wake` by sending this task back onto the task queue
+        // so that it will be polled again by the executor.
+        //
+        // NOTE: unless it's queued already, or being polled: then it's queued
+        //       once the poll is over, see `Task::run`
+        arc_self.registry.metrics.record_wakeup();
+        let mut state = State::load(&arc_self.state);
+        loop {
+            let new_state = match state {
+                State::Idle => State::Scheduled,
+                State::Running => State::Notified,
+                State::Scheduled | State::Notified | State::Complete => return,
+            };
+            match State::transition(&arc_self.state, state, new_state) {
+                Ok(()) if new_state == State::Scheduled => {
+                    arc_self.mark_scheduled();
+                    let cloned = arc_self.clone();
+                    arc_self.scheduler.schedule(cloned);
+                    return;
+                }
+                Ok(()) => return,
+                Err(actual) => state = actual,
+            }
+        }
+    }
+}
+
+impl Executor {
+    /// Sets the hook called when a task panics.
+    ///
+    /// Either way, the panicking task is dropped, and the executor
+    /// carries on with the other tasks.
+    pub fn set_panic_hook(&mut self, hook: impl Fn(&(dyn Any + Send)) + Send + Sync + 'static) {
+        self.hooks.panic = Some(Arc::new(hook));
+    }
+
+    /// Reports every poll taking longer than the `threshold`, with the id
+    /// of the task and where it was spawned. It's meant to catch blocking calls
+    /// (e.g. `std::thread::sleep`) in the futures during development.
+    ///
+    /// The slow polls are printed to stderr, unless a hook is set
+    /// with `set_slow_poll_hook`.
+    pub fn set_slow_poll_threshold(&mut self, threshold: Duration) {
+        self.hooks.slow_poll_threshold = Some(threshold);
+    }
+
+    /// Sets the hook called with the slow polls, see `set_slow_poll_threshold`.
+    pub fn set_slow_poll_hook(&mut self, hook: impl Fn(&SlowPoll) + Send + Sync + 'static) {
+        self.hooks.slow_poll = Some(Arc::new(hook));
+    }
+
+    /// Limits the number of live tasks, `Spawner::spawn` fails
+    /// with `SpawnError::AtCapacity` beyond it.
+    pub fn set_max_tasks(&self, max_tasks: usize) {
+        self.registry.set_max(max_tasks);
+    }
+
+    /// Returns a snapshot of the metrics of the executor.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.registry.metrics()
+    }
+
+    /// Returns the pool running the closures given to `spawn_blocking`,
+    /// e.g. to limit its number of threads.
+    pub fn blocking_pool(&self) -> &BlockingPool {
+        &self.blocking
+    }
+
+    /// Returns a handle to shut the executor down, see `ShutdownHandle::shutdown`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            ready_queue: self.ready_queue.clone(),
+            registry: self.registry.clone(),
+        }
+    }
+
+    pub fn run(&self) -> ShutdownReport {
+        // Make the `TimerFuture`s created while polling register in our timer driver
+        let _timer = self.timer.enter();
+        // ... and the I/O sources in our reactor
+        let _reactor = self.ready_queue.reactor.enter();
+        // ... and the blocking closures in our pool
+        let _blocking = self.blocking.enter();
+
+        let mut polls = 0u32;
+        while let Some(task) = self.ready_queue.pop(&self.registry) {
+            task.run(&self.hooks);
+
+            // NOTE: the reactor is mostly polled while the queue is empty,
+            //       and every now and then otherwise, so I/O events aren't
+            //       starved by tasks that keep waking each other
+            polls = polls.wrapping_add(1);
+            if polls.is_multiple_of(64) {
+                self.ready_queue.reactor.turn(Some(Duration::ZERO));
+            }
+        }
+
+        // Past the deadline of a shutdown, the remaining tasks are cancelled
+        ShutdownReport {
+            cancelled: self.cancel_all(),
+        }
+    }
+
+    /// Cancels the tasks that aren't complete yet: they are queued once more,
+    /// to drop their futures on this thread
+    fn cancel_all(&self) -> Vec<CancelledTask> {
+        let cancelled = self.registry.cancel_all();
+        while let Some(task) = self.ready_queue.try_pop() {
+            task.run(&self.hooks);
+        }
+        cancelled
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        // NOTE: the queued tasks hold the queue itself (through their scheduler),
+        //       so they would leak, along with their futures, if it was never run
+        self.cancel_all();
+    }
+}
+
+impl<F: Future<Output = ()> + Send + 'static> Runnable for Task<F> {
+    fn run(self: Arc<Self>, hooks: &Hooks) {
+        let metrics = &self.registry.metrics;
+        let polled_at = metrics.now();
+        let start = Instant::now();
+
+        self.state.store(State::Running as u8, Ordering::Release);
+        let complete = self.poll(hooks.panic.as_ref());
+
+        let duration = start.elapsed();
+        let polls = self.polls.fetch_add(1, Ordering::Relaxed) + 1;
+        metrics.record_poll(
+            self.scheduled_at.load(Ordering::Relaxed),
+            polled_at,
+            duration,
+        );
+        hooks.check_poll_duration(self.id, self.location, duration);
+
+        if complete {
+            self.state.store(State::Complete as u8, Ordering::Release);
+            metrics.record_completion(polls);
+            self.registry.decrement();
+        } else if State::transition(&self.state, State::Running, State::Idle).is_err() {
+            // Woken while being polled, so it's queued once again
+            self.mark_scheduled();
+            self.state.store(State::Scheduled as u8, Ordering::Release);
+            self.scheduler.schedule(self.clone());
+        }
+    }
+
+    fn cancel(self: Arc<Self>) -> bool {
+        if State::load(&self.state) == State::Complete {
+            return false;
+        }
+        Abort::abort(self);
+        true
+    }
+
+    fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn location(&self) -> &'static Location<'static> {
+        self.location
+    }
+}
+
+impl<F: Future<Output = ()> + Send + 'static> Abort for Task<F> {
+    fn abort(self: Arc<Self>) {
+        self.aborted.store(true, Ordering::Release);
+        ArcWake::wake(self);
+    }
+}
+
+impl<F: Future<Output = ()> + Send + 'static> Task<F> {
+    /// Stamps the time the task is queued at, for the queue delay metrics
+    fn mark_scheduled(&self) {
+        self.scheduled_at
+            .store(self.registry.metrics.now(), Ordering::Relaxed);
+    }
+
+    /// Returns whether the task is complete, after polling its future.
+    fn poll(self: &Arc<Self>, panic_hook: Option<&PanicHook>) -> bool {
+        // SAFETY: the task is `Running`, no other thread accesses the future
+        let future_slot = unsafe { &mut *self.future.get() };
+
+        // If the future has not yet completed (is still Some),
+        // poll it in an attempt to complete it.
+        if self.aborted.load(Ordering::Acquire) {
+            // Dropping the future resolves its `JoinHandle` as cancelled
+            *future_slot = None;
+        } else if let Some(future) = future_slot {
+            // Create a `LocalWaker` from the task itself
+            let waker = waker_ref(self);
+            let context = &mut Context::from_waker(&waker);
+            // SAFETY: the future is stored inside the task allocation,
+            //         and is never moved out of it, only dropped in place
+            let future = unsafe { Pin::new_unchecked(future) };
+            //
+            // NOTE: the poll is wrapped with `catch_unwind`, so a panic tears down
+            //       this task only, and doesn't unwind through the executor loop
+            //
+            // NOTE: every poll gets a fresh budget, see `coop`
+            let poll = || coop::budget(|| future.poll(context));
+            match panic::catch_unwind(AssertUnwindSafe(poll)) {
+                // We're not done processing the future, so leave it
+                // in its task to be run again in the future.
+                Ok(poll) if poll.is_pending() => return false,
+                Ok(_) => *future_slot = None,
+                Err(payload) => {
+                    if let Some(panic_hook) = panic_hook {
+                        panic_hook(&*payload);
+                    }
+                    self.join_state.report_panic(payload);
+                    // NOTE: a destructor that panics too must not take the executor down
+                    let _ = panic::catch_unwind(AssertUnwindSafe(|| *future_slot = None));
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{join_handle::JoinError, timer_future::TimerFuture};
+    use std::{sync::atomic::AtomicUsize, task::Poll, time::Duration};
+
+    #[test]
+    fn panicking_task_does_not_stop_the_executor() {
+        let (mut executor, spawner) = new_executor_and_spawner();
+        let panics = Arc::new(AtomicUsize::new(0));
+        let hook_panics = panics.clone();
+        executor.set_panic_hook(move |payload| {
+            assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+            hook_panics.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let panicked = spawner
+            .spawn(async {
+                TimerFuture::new(Duration::from_millis(1)).await;
+                panic!("boom");
+            })
+            .unwrap();
+        let survivor = spawner
+            .spawn(async {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                "survived"
+            })
+            .unwrap();
+        let results = spawner
+            .spawn(async move {
+                let payload = match panicked.await {
+                    Err(JoinError::Panic(payload)) => payload,
+                    _ => unreachable!(),
+                };
+                (
+                    *payload.downcast::<&str>().unwrap(),
+                    survivor.await.unwrap(),
+                )
+            })
+            .unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        spawner
+            .spawn(async move { tx.send(results.await.unwrap()).unwrap() })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(rx.recv().unwrap(), ("boom", "survived"));
+        assert_eq!(panics.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn spawn_pushes_back_instead_of_panicking() {
+        let (executor, spawner) = new_executor_and_spawner();
+        executor.set_max_tasks(2);
+
+        let mut woken = false;
+        spawner
+            .spawn(futures::future::poll_fn(move |cx| {
+                if woken {
+                    return Poll::Ready(());
+                }
+                // Waking a task far more often than the course `sync_channel` could hold
+                for _ in 0..100_000 {
+                    cx.waker().wake_by_ref();
+                }
+                woken = true;
+                Poll::Pending
+            }))
+            .unwrap();
+        spawner.spawn(async {}).unwrap();
+        assert_eq!(spawner.spawn(async {}).err(), Some(SpawnError::AtCapacity));
+        drop(spawner);
+
+        executor.run();
+        assert_eq!(executor.registry.live.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn wakeups_are_deduplicated() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let polls = Arc::new(AtomicUsize::new(0));
+        let waker = Arc::new(Mutex::new(None::<std::task::Waker>));
+
+        let task_polls = polls.clone();
+        let task_waker = waker.clone();
+        spawner
+            .spawn(futures::future::poll_fn(move |cx| {
+                match task_polls.fetch_add(1, Ordering::SeqCst) {
+                    0 => {
+                        // Woken many times while polled, the task is polled once more only
+                        for _ in 0..10 {
+                            cx.waker().wake_by_ref();
+                        }
+                        Poll::Pending
+                    }
+                    1 => {
+                        *task_waker.lock().unwrap() = Some(cx.waker().clone());
+                        Poll::Pending
+                    }
+                    _ => Poll::Ready(()),
+                }
+            }))
+            .unwrap();
+        spawner
+            .spawn(async move {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                // Woken many times before its next poll, the task is queued once only
+                let waker = waker.lock().unwrap().take().unwrap();
+                for _ in 0..10 {
+                    waker.wake_by_ref();
+                }
+            })
+            .unwrap();
+
+        drop(spawner);
+        executor.run();
+        assert_eq!(polls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn shutdown_cancels_the_tasks_left_after_the_grace_period() {
+        let (executor, spawner) = new_executor_and_spawner();
+
+        let quick = spawner
+            .spawn(async {
+                TimerFuture::new(Duration::from_millis(10)).await;
+                "done"
+            })
+            .unwrap();
+        let slow = spawner
+            .spawn(TimerFuture::new(Duration::from_secs(60)))
+            .unwrap();
+        // Never woken, but its waker is kept around, so it's never dropped either
+        let waker = Arc::new(Mutex::new(None));
+        let task_waker = waker.clone();
+        let stuck = spawner
+            .spawn(futures::future::poll_fn(move |cx| {
+                *task_waker.lock().unwrap() = Some(cx.waker().clone());
+                Poll::<()>::Pending
+            }))
+            .unwrap();
+
+        executor
+            .shutdown_handle()
+            .shutdown(Duration::from_millis(100));
+        assert_eq!(spawner.spawn(async {}).err(), Some(SpawnError::Shutdown));
+
+        // NOTE: the `spawner` is still alive
+        let report = executor.run();
+
+        let cancelled = report
+            .cancelled
+            .iter()
+            .map(|task| (task.task_id, task.location.file()))
+            .collect::<Vec<_>>();
+        assert_eq!(cancelled, [(1, file!()), (2, file!())]);
+        assert_eq!(futures::executor::block_on(quick).unwrap(), "done");
+        assert!(futures::executor::block_on(slow)
+            .unwrap_err()
+            .is_cancelled());
+        assert!(futures::executor::block_on(stuck)
+            .unwrap_err()
+            .is_cancelled());
+        drop(spawner);
+    }
+
+    #[test]
+    fn shutdown_without_a_deadline_waits_for_every_task() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let task = spawner
+            .spawn(async {
+                TimerFuture::new(Duration::from_millis(50)).await;
+                "done"
+            })
+            .unwrap();
+
+        executor.shutdown_handle().shutdown(Duration::MAX);
+        let report = executor.run();
+
+        assert_eq!(report, ShutdownReport::default());
+        assert_eq!(futures::executor::block_on(task).unwrap(), "done");
+        drop(spawner);
+    }
+
+    #[test]
+    fn dropping_an_executor_drops_its_tasks() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let guard = Arc::new(());
+
+        let task_guard = guard.clone();
+        let task = spawner
+            .spawn(async move {
+                let _guard = task_guard;
+            })
+            .unwrap();
+        drop(spawner);
+        drop(executor);
+
+        assert_eq!(Arc::strong_count(&guard), 1);
+        assert!(futures::executor::block_on(task)
+            .unwrap_err()
+            .is_cancelled());
+    }
+
+    #[test]
+    fn slow_polls_are_reported_with_the_spawn_location() {
+        let (mut executor, spawner) = new_executor_and_spawner();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let hook_reports = reports.clone();
+        executor.set_slow_poll_threshold(Duration::from_millis(20));
+        executor.set_slow_poll_hook(move |slow_poll| {
+            hook_reports.lock().unwrap().push(*slow_poll);
+        });
+
+        spawner.spawn(async {}).unwrap();
+        let line = line!() + 2;
+        spawner
+            .spawn(async {
+                // Blocks the executor thread, which must never happen in async code
+                std::thread::sleep(Duration::from_millis(50));
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].task_id, 1);
+        assert_eq!(reports[0].location.file(), file!());
+        assert_eq!(reports[0].location.line(), line);
+        assert!(reports[0].duration >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn higher_classes_get_more_polls_without_starving_the_others() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let spawn = |priority, name| {
+            let order = order.clone();
+            spawner
+                .spawn_with_priority(priority, async move {
+                    order.lock().unwrap().push(name);
+                })
+                .unwrap();
+        };
+        spawn(Priority::Background, "background");
+        spawn(Priority::Normal, "normal");
+        for _ in 0..8 {
+            spawn(Priority::High, "high");
+        }
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            [
+                "high",
+                "high",
+                "high",
+                "high",
+                "normal",
+                // The turn of the empty `Normal` class goes to `High`
+                "high",
+                "background",
+                "high",
+                "high",
+                "high",
+            ]
+        );
+    }
+}
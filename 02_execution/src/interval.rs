@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: a periodic `TimerFuture`: a single registration in the timer driver,
+//       re-armed with `reset` after each tick
+
+use {
+    crate::timer_future::{Handle, TimerFuture},
+    futures::stream::Stream,
+    std::{
+        convert::TryFrom,
+        future::Future,
+        pin::Pin,
+        task::{ready, Context, Poll},
+        time::{Duration, Instant},
+    },
+};
+
+/// What an `Interval` does with the ticks it missed, e.g. since the consumer
+/// was busy for longer than the period
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissedTickBehavior {
+    /// Yields the missed ticks at once, to catch up with the schedule
+    #[default]
+    Burst,
+
+    /// Yields one tick, and starts the schedule over from it
+    Delay,
+
+    /// Yields one tick, and drops the other missed ticks of the schedule
+    Skip,
+}
+
+/// Creates an `Interval` yielding every `period`, starting right away.
+///
+/// Panics if the `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    interval_at(Handle::current().now(), period)
+}
+
+/// Creates an `Interval` yielding every `period`, starting at the `start`.
+///
+/// The `start` is on the clock of the current timer driver, see `TimerFuture::at`.
+/// Panics if the `period` is zero.
+pub fn interval_at(start: Instant, period: Duration) -> Interval {
+    assert!(period > Duration::ZERO, "the period must be non-zero");
+
+    Interval {
+        timer: TimerFuture::at(start),
+        handle: Handle::current(),
+        next: start,
+        period,
+        missed_tick_behavior: MissedTickBehavior::default(),
+    }
+}
+
+/// A stream of ticks at a fixed period, yielding the instant each tick was scheduled for
+pub struct Interval {
+    /// Registered at the `next` tick
+    timer: TimerFuture,
+
+    /// Driver of the `timer`, for the current time
+    handle: Handle,
+
+    next: Instant,
+    period: Duration,
+    missed_tick_behavior: MissedTickBehavior,
+}
+
+impl Interval {
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    pub fn missed_tick_behavior(&self) -> MissedTickBehavior {
+        self.missed_tick_behavior
+    }
+
+    pub fn set_missed_tick_behavior(&mut self, behavior: MissedTickBehavior) {
+        self.missed_tick_behavior = behavior;
+    }
+
+    /// Completes at the next tick.
+    pub async fn tick(&mut self) -> Instant {
+        futures::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context<'_>) -> Poll<Instant> {
+        ready!(Pin::new(&mut self.timer).poll(cx));
+
+        let tick = self.next;
+        let now = self.handle.now();
+        self.next = match self.missed_tick_behavior {
+            MissedTickBehavior::Burst => tick + self.period,
+            MissedTickBehavior::Delay if now > tick + self.period => now + self.period,
+            MissedTickBehavior::Skip => {
+                let missed =
+                    now.saturating_duration_since(tick).as_nanos() / self.period.as_nanos();
+                tick + self.period * u32::try_from(missed + 1).unwrap_or(u32::MAX)
+            }
+            MissedTickBehavior::Delay => tick + self.period,
+        };
+        self.timer.reset(self.next);
+
+        Poll::Ready(tick)
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_tick(cx).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulation::new_simulation_and_spawner;
+    use futures::{executor::block_on, StreamExt};
+
+    /// Returns the ticks of an interval of 10s, in seconds since its start,
+    /// with the consumer busy for 25s after the second one
+    fn ticks(behavior: MissedTickBehavior) -> Vec<u64> {
+        let (mut simulation, spawner) = new_simulation_and_spawner(0);
+        let ticks = spawner
+            .spawn(async move {
+                let start = Handle::current().now();
+                let mut interval = interval(Duration::from_secs(10));
+                interval.set_missed_tick_behavior(behavior);
+
+                let mut ticks = Vec::new();
+                while let Some(tick) = interval.next().await {
+                    ticks.push((tick - start).as_secs());
+                    match ticks.len() {
+                        2 => TimerFuture::new(Duration::from_secs(25)).await,
+                        5 => return ticks,
+                        _ => {}
+                    }
+                }
+                unreachable!()
+            })
+            .unwrap();
+        simulation.run();
+
+        block_on(ticks).unwrap()
+    }
+
+    #[test]
+    fn missed_ticks_are_handled_as_configured() {
+        assert_eq!(ticks(MissedTickBehavior::Burst), [0, 10, 20, 30, 40]);
+        assert_eq!(ticks(MissedTickBehavior::Delay), [0, 10, 20, 45, 55]);
+        assert_eq!(ticks(MissedTickBehavior::Skip), [0, 10, 20, 40, 50]);
+    }
+}
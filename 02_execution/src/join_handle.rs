@@ -0,0 +1,264 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::coop;
+use std::{
+    any::Any,
+    fmt,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{ready, Context, Poll, Waker},
+    thread,
+};
+
+/// A future resolving to the output of a spawned task.
+///
+/// Dropping the handle detaches the task, it keeps running in the background.
+pub struct JoinHandle<T> {
+    join_state: Arc<Mutex<JoinState<T>>>,
+
+    /// NOTE: a weak reference, since a live task keeps its executor running
+    task: Weak<dyn Abort>,
+}
+
+/// Shared state between the `JoinHandle` and its task
+pub(crate) struct JoinState<T> {
+    /// Whether or not the task has finished (or has been cancelled)
+    completed: bool,
+
+    /// The result of the task, until it's taken by the `JoinHandle`
+    output: Option<Result<T, JoinError>>,
+
+    /// The waker for the task awaiting the `JoinHandle`
+    waker: Option<Waker>,
+}
+
+impl<T> JoinState<T> {
+    /// Stores the `output`, unless the task has finished already
+    fn complete(this: &Mutex<Self>, output: Result<T, JoinError>) {
+        let mut join_state = this.lock().unwrap();
+        if join_state.completed {
+            return;
+        }
+        join_state.completed = true;
+        join_state.output = Some(output);
+        let waker = join_state.waker.take();
+        drop(join_state);
+
+        // NOTE: woken outside of the lock, so a panicking waker can't poison it
+        if let Some(waker) = waker {
+            waker.wake()
+        }
+    }
+}
+
+/// The reason a task didn't produce its output
+pub enum JoinError {
+    /// The task has been aborted or dropped by its executor before completion
+    Cancelled,
+
+    /// The task panicked, with the given payload
+    Panic(Box<dyn Any + Send + 'static>),
+}
+
+impl JoinError {
+    pub fn is_cancelled(&self) -> bool {
+        matches!(self, JoinError::Cancelled)
+    }
+
+    pub fn is_panic(&self) -> bool {
+        matches!(self, JoinError::Panic(_))
+    }
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => f.write_str("Cancelled"),
+            JoinError::Panic(_) => f.write_str("Panic(..)"),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Cancelled => f.write_str("task was cancelled"),
+            JoinError::Panic(_) => f.write_str("task panicked"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+impl<T> JoinHandle<T> {
+    /// Cancels the task: its future is dropped the next time the executor
+    /// picks it up, and the handle resolves to `Err(JoinError::Cancelled)`.
+    pub fn abort(&self) {
+        if let Some(task) = self.task.upgrade() {
+            task.abort();
+        }
+    }
+
+    pub(crate) fn new(join_state: Arc<Mutex<JoinState<T>>>, task: Weak<dyn Abort>) -> Self {
+        JoinHandle { join_state, task }
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(coop::poll_proceed(cx));
+
+        let mut join_state = self.join_state.lock().unwrap();
+        match join_state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                assert!(
+                    !join_state.completed,
+                    "`JoinHandle` polled after completion"
+                );
+                join_state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Lets a `JoinHandle` cancel its task
+pub(crate) trait Abort: Send + Sync {
+    /// Makes the executor drop the future instead of polling it.
+    fn abort(self: Arc<Self>);
+}
+
+/// Completes the `JoinHandle` of a task whose future panicked
+pub(crate) trait ReportPanic {
+    fn report_panic(&self, payload: Box<dyn Any + Send + 'static>);
+}
+
+impl<T> ReportPanic for Mutex<JoinState<T>> {
+    fn report_panic(&self, payload: Box<dyn Any + Send + 'static>) {
+        JoinState::complete(self, Err(JoinError::Panic(payload)));
+    }
+}
+
+/// Completes the `JoinHandle` with `Err(JoinError::Cancelled)`
+/// if the task is dropped before completion
+struct CancelOnDrop<T>(Arc<Mutex<JoinState<T>>>);
+
+impl<T> Drop for CancelOnDrop<T> {
+    fn drop(&mut self) {
+        // NOTE: when the future is dropped by the unwinding of a panic,
+        //       the executor reports the panic instead
+        if !thread::panicking() {
+            JoinState::complete(&self.0, Err(JoinError::Cancelled));
+        }
+    }
+}
+
+/// Wraps the `future` of a task, so its output is sent to the `JoinHandle`
+/// created from the returned `JoinState`.
+///
+/// Panics are caught by the executor, and reported through the `JoinState` too.
+///
+/// NOTE: the returned future is `Send` as long as the `future` is
+pub(crate) fn join_future<T>(
+    future: impl Future<Output = T>,
+) -> (impl Future<Output = ()>, Arc<Mutex<JoinState<T>>>) {
+    let join_state = Arc::new(Mutex::new(JoinState {
+        completed: false,
+        output: None,
+        waker: None,
+    }));
+
+    let cancel_on_drop = CancelOnDrop(join_state.clone());
+    let future = async move {
+        let output = future.await;
+        JoinState::complete(&cancel_on_drop.0, Ok(output));
+    };
+
+    (future, join_state)
+}
+
+/// Wraps a blocking `f`, so its output (or its panic) is sent to the `JoinHandle`
+/// created from the returned `JoinState`.
+///
+/// NOTE: if the returned closure is dropped without being called,
+///       the `JoinHandle` resolves to `Err(JoinError::Cancelled)`
+pub(crate) fn join_closure<T>(f: impl FnOnce() -> T) -> (impl FnOnce(), Arc<Mutex<JoinState<T>>>) {
+    let join_state = Arc::new(Mutex::new(JoinState {
+        completed: false,
+        output: None,
+        waker: None,
+    }));
+
+    let cancel_on_drop = CancelOnDrop(join_state.clone());
+    let f = move || {
+        let output = panic::catch_unwind(AssertUnwindSafe(f)).map_err(JoinError::Panic);
+        JoinState::complete(&cancel_on_drop.0, output);
+    };
+
+    (f, join_state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::new_executor_and_spawner, timer_future::TimerFuture};
+    use std::time::Duration;
+
+    #[test]
+    fn resolves_to_output_panic_or_cancellation() {
+        let (executor, spawner) = new_executor_and_spawner();
+
+        let answer = spawner.spawn(async { 42 }).unwrap();
+        let panicked = spawner.spawn(async { panic!("boom") }).unwrap();
+        let aborted = spawner
+            .spawn(async {
+                TimerFuture::new(Duration::from_secs(60)).await;
+            })
+            .unwrap();
+        aborted.abort();
+
+        let results = Arc::new(Mutex::new(None));
+        let task_results = results.clone();
+        spawner
+            .spawn(async move {
+                *task_results.lock().unwrap() = Some((
+                    answer.await.unwrap(),
+                    panicked.await.unwrap_err().is_panic(),
+                    aborted.await.unwrap_err().is_cancelled(),
+                ));
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(*results.lock().unwrap(), Some((42, true, true)));
+    }
+
+    #[test]
+    fn a_panicking_waker_does_not_poison_the_handle() {
+        struct PanicOnWake;
+
+        impl futures::task::ArcWake for PanicOnWake {
+            fn wake_by_ref(_: &Arc<Self>) {
+                panic!("boom");
+            }
+        }
+
+        let (executor, spawner) = new_executor_and_spawner();
+        let mut handle = spawner.spawn(async { 42 }).unwrap();
+        let waker = futures::task::waker(Arc::new(PanicOnWake));
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut handle).poll(&mut cx).is_pending());
+
+        // The task panics when it wakes the handle, after storing its output
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(futures::executor::block_on(handle).unwrap(), 42);
+    }
+}
@@ -0,0 +1,25 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// --- Index ---
+// 2.2. Task Wakeups with Waker
+//      ^ timer_future.rs
+// 2.3. Applied: Build an Executor
+//      ^ executor.rs
+
+pub mod blocking;
+pub mod channel;
+pub mod coop;
+pub mod executor;
+pub mod interval;
+pub mod join_handle;
+pub mod local_executor;
+pub mod metrics;
+pub mod net;
+pub mod reactor;
+pub mod scope;
+pub mod simulation;
+pub mod sync;
+pub mod task_local;
+pub mod thread_pool;
+pub mod timeout;
+pub mod timer_future;
@@ -0,0 +1,399 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: a variant of the `Executor` from 2.3. Applied: Build an Executor for futures
+//       that are not `Send` (e.g. holding an `Rc` or a `RefCell`). The executor
+//       and its spawners are `!Send` themselves, so the tasks are only ever polled
+//       (and dropped) on the thread that created them.
+//
+//       Wakers have to be `Send` though. A waker of a local task only holds
+//       the id of the task, so waking it from the thread of a timer driver,
+//       a reactor or a blocking pool just queues that id for the owner thread,
+//       and never touches the future. A wakeup from any other thread is most
+//       likely a mistake: it's rejected, and the owner thread panics in `run`
+//       (rather than the waking thread, which may not expect it).
+
+use {
+    crate::{
+        blocking::BlockingPool,
+        coop,
+        executor::PanicHook,
+        join_handle::{join_future, Abort, JoinHandle, ReportPanic},
+        reactor::{self, Reactor},
+        timer_future::TimerDriver,
+    },
+    futures::task::{waker_ref, ArcWake},
+    std::{
+        any::Any,
+        cell::{Cell, RefCell},
+        collections::{HashMap, VecDeque},
+        future::Future,
+        panic::{self, AssertUnwindSafe},
+        pin::Pin,
+        rc::Rc,
+        sync::{
+            atomic::{self, AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        task::Context,
+        thread::{self, ThreadId},
+        time::Duration,
+    },
+};
+
+/// Task executor that runs `!Send` futures on the current thread.
+pub struct LocalExecutor {
+    tasks: Rc<LocalTasks>,
+
+    /// Fires the `TimerFuture`s created by the tasks of this executor.
+    timer: TimerDriver,
+
+    /// Polled while no task is ready, on the thread of the executor.
+    reactor: Reactor,
+
+    /// Runs the closures given to `spawn_blocking` by the tasks of this executor.
+    blocking: BlockingPool,
+
+    panic_hook: Option<PanicHook>,
+}
+
+thread_local! {
+    /// Whether the current thread is the one of a timer driver, a reactor
+    /// or a blocking pool, see `allow_local_wakeups`
+    static DRIVER_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Allows the current thread to wake local tasks, whatever their executor.
+///
+/// NOTE: called by the threads of our drivers, which only wake the tasks
+pub(crate) fn allow_local_wakeups() {
+    DRIVER_THREAD.with(|driver_thread| driver_thread.set(true));
+}
+
+/// `LocalSpawner` spawns new `!Send` futures onto its `LocalExecutor`.
+#[derive(Clone)]
+pub struct LocalSpawner {
+    tasks: Rc<LocalTasks>,
+}
+
+/// Tasks of a `LocalExecutor`, accessed from its thread only
+struct LocalTasks {
+    /// Tasks by id, except the one being polled
+    tasks: RefCell<HashMap<usize, LocalTask>>,
+
+    /// Number of live tasks, including the one being polled
+    live: Cell<usize>,
+    next_id: Cell<usize>,
+    ready_queue: Arc<ReadyQueue>,
+}
+
+/// A `!Send` future that can reschedule itself to be polled by a `LocalExecutor`.
+struct LocalTask {
+    /// In-progress future that should be pushed to completion.
+    future: Pin<Box<dyn Future<Output = ()>>>,
+
+    /// Hands the payload over to the `JoinHandle`, if the future panics.
+    join_state: Arc<dyn ReportPanic>,
+
+    waker: Arc<LocalWaker>,
+}
+
+/// Ids of the tasks ready to be polled.
+///
+/// NOTE: it's the only part shared with other threads, through the wakers
+struct ReadyQueue {
+    ids: Mutex<VecDeque<usize>>,
+
+    /// Whether the executor waits for a task in `Reactor::turn`
+    sleeping: AtomicBool,
+
+    /// Unparks the reactor of the executor, to run a newly queued task
+    reactor: reactor::Handle,
+
+    /// The thread that created the executor
+    owner: ThreadId,
+
+    /// Set by a wakeup from another thread than the owner or a driver one
+    foreign_wakeup: AtomicBool,
+}
+
+/// Waker of a `LocalTask`, the task itself stays on the owner thread
+struct LocalWaker {
+    id: usize,
+
+    /// Whether the task is in the `ReadyQueue` already
+    scheduled: AtomicBool,
+
+    /// Set by `JoinHandle::abort`, the future is dropped instead of being polled.
+    aborted: AtomicBool,
+
+    ready_queue: Arc<ReadyQueue>,
+}
+
+pub fn new_local_executor_and_spawner() -> (LocalExecutor, LocalSpawner) {
+    let reactor = Reactor::new();
+    let tasks = Rc::new(LocalTasks {
+        tasks: RefCell::new(HashMap::new()),
+        live: Cell::new(0),
+        next_id: Cell::new(0),
+        ready_queue: Arc::new(ReadyQueue {
+            ids: Mutex::new(VecDeque::new()),
+            sleeping: AtomicBool::new(false),
+            reactor: reactor.handle(),
+            owner: thread::current().id(),
+            foreign_wakeup: AtomicBool::new(false),
+        }),
+    });
+    let executor = LocalExecutor {
+        tasks: tasks.clone(),
+        timer: TimerDriver::new(),
+        reactor,
+        blocking: BlockingPool::new(),
+        panic_hook: None,
+    };
+    (executor, LocalSpawner { tasks })
+}
+
+impl LocalSpawner {
+    pub fn spawn<T: 'static>(&self, future: impl Future<Output = T> + 'static) -> JoinHandle<T> {
+        let id = self.tasks.next_id.get();
+        self.tasks.next_id.set(id + 1);
+
+        let (future, join_state) = join_future(future);
+        let waker = Arc::new(LocalWaker {
+            id,
+            scheduled: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            ready_queue: self.tasks.ready_queue.clone(),
+        });
+        let join_handle = JoinHandle::new(join_state.clone(), Arc::downgrade(&waker) as _);
+
+        let task = LocalTask {
+            future: Box::pin(future),
+            join_state,
+            waker: waker.clone(),
+        };
+        self.tasks.tasks.borrow_mut().insert(id, task);
+        self.tasks.live.set(self.tasks.live.get() + 1);
+        ArcWake::wake(waker);
+        join_handle
+    }
+}
+
+impl LocalWaker {
+    /// Queues the id of the task, unless it's queued already
+    fn schedule(&self) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            self.ready_queue.ids.lock().unwrap().push_back(self.id);
+            self.ready_queue.unpark();
+        }
+    }
+}
+
+impl ArcWake for LocalWaker {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let ready_queue = &arc_self.ready_queue;
+        if thread::current().id() == ready_queue.owner || DRIVER_THREAD.with(Cell::get) {
+            arc_self.schedule();
+        } else {
+            // NOTE: reported by the owner thread, see `ReadyQueue::pop`
+            ready_queue.foreign_wakeup.store(true, Ordering::Release);
+            ready_queue.unpark();
+        }
+    }
+}
+
+impl Abort for LocalWaker {
+    fn abort(self: Arc<Self>) {
+        // NOTE: unlike a wakeup, aborting from another thread is fine:
+        //       the future is dropped by the owner thread either way
+        self.aborted.store(true, Ordering::Release);
+        self.schedule();
+    }
+}
+
+impl LocalExecutor {
+    /// Sets the hook called when a task panics, see `Executor::set_panic_hook`.
+    pub fn set_panic_hook(&mut self, hook: impl Fn(&(dyn Any + Send)) + Send + Sync + 'static) {
+        self.panic_hook = Some(Arc::new(hook));
+    }
+
+    /// Runs the tasks until every one of them is complete.
+    ///
+    /// NOTE: unlike `Executor::run`, it doesn't wait for the spawners to be dropped:
+    ///       they are bound to this thread, so only the tasks can spawn while it runs
+    ///
+    /// Panics if a task is woken from another thread than this one
+    /// or the one of a driver (e.g. by a thread it spawned itself).
+    pub fn run(&self) {
+        // Make the `TimerFuture`s created while polling register in our timer driver
+        let _timer = self.timer.enter();
+        // ... and the I/O sources in our reactor
+        let _reactor = self.reactor.enter();
+        // ... and the blocking closures in our pool
+        let _blocking = self.blocking.enter();
+
+        let mut polls = 0u32;
+        while self.tasks.live.get() > 0 {
+            let id = self.tasks.ready_queue.pop(&self.reactor);
+            self.run_task(id);
+
+            // NOTE: so I/O events aren't starved, see `Executor::run`
+            polls = polls.wrapping_add(1);
+            if polls.is_multiple_of(64) {
+                self.reactor.turn(Some(Duration::ZERO));
+            }
+        }
+    }
+
+    fn run_task(&self, id: usize) {
+        // NOTE: the task is taken out while being polled, since its future
+        //       may spawn new tasks
+        let mut task = match self.tasks.tasks.borrow_mut().remove(&id) {
+            Some(task) => task,
+            // A stale wakeup of a completed task
+            None => return,
+        };
+        task.waker.scheduled.store(false, Ordering::Release);
+
+        let complete = if task.waker.aborted.load(Ordering::Acquire) {
+            true
+        } else {
+            let local_waker = task.waker.clone();
+            let waker = waker_ref(&local_waker);
+            let context = &mut Context::from_waker(&waker);
+            let poll = || coop::budget(|| task.future.as_mut().poll(context));
+            match panic::catch_unwind(AssertUnwindSafe(poll)) {
+                Ok(poll) => poll.is_ready(),
+                Err(payload) => {
+                    if let Some(panic_hook) = &self.panic_hook {
+                        panic_hook(&*payload);
+                    }
+                    task.join_state.report_panic(payload);
+                    true
+                }
+            }
+        };
+
+        if complete {
+            self.tasks.live.set(self.tasks.live.get() - 1);
+            // NOTE: a destructor that panics too must not take the executor down
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(task)));
+        } else {
+            self.tasks.tasks.borrow_mut().insert(id, task);
+        }
+    }
+}
+
+impl ReadyQueue {
+    /// Waits for the id of the next task to run, polling the `reactor` meanwhile.
+    ///
+    /// Panics once a task is woken from a foreign thread.
+    fn pop(&self, reactor: &Reactor) -> usize {
+        loop {
+            assert!(
+                !self.foreign_wakeup.load(Ordering::Acquire),
+                "a local task was woken from another thread than the one of its `LocalExecutor`"
+            );
+            if let Some(id) = self.ids.lock().unwrap().pop_front() {
+                return id;
+            }
+
+            // NOTE: same handshake as the `Executor` with its `Scheduler`
+            self.sleeping.store(true, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+            if self.ids.lock().unwrap().is_empty() && !self.foreign_wakeup.load(Ordering::SeqCst) {
+                reactor.turn(None);
+            }
+            self.sleeping.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Interrupts the executor waiting in `pop`, if it is
+    fn unpark(&self) {
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) {
+            self.reactor.unpark();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blocking::spawn_blocking,
+        net::{TcpListener, TcpStream},
+        timer_future::TimerFuture,
+    };
+
+    #[test]
+    fn runs_non_send_futures() {
+        let (executor, spawner) = new_local_executor_and_spawner();
+        let log = Rc::new(RefCell::new(Vec::new()));
+
+        let task_log = log.clone();
+        let inner_spawner = spawner.clone();
+        let outer = spawner.spawn(async move {
+            let inner_log = task_log.clone();
+            let inner = inner_spawner.spawn(async move {
+                // Woken from the timer driver thread
+                TimerFuture::new(Duration::from_millis(10)).await;
+                inner_log.borrow_mut().push("inner");
+                Rc::new("output")
+            });
+            task_log.borrow_mut().push("outer");
+            inner.await.unwrap()
+        });
+        let check = spawner.spawn(async move {
+            assert_eq!(*outer.await.unwrap(), "output");
+        });
+        executor.run();
+
+        assert!(futures::executor::block_on(check).is_ok());
+        assert_eq!(*log.borrow(), ["outer", "inner"]);
+    }
+
+    #[test]
+    fn tasks_are_woken_by_the_drivers() {
+        let (executor, spawner) = new_local_executor_and_spawner();
+        // Created before `run`, so in the process-wide reactor and timer driver
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timer = TimerFuture::new(Duration::from_millis(10));
+
+        let inner_spawner = spawner.clone();
+        let check = spawner.spawn(async move {
+            let local = Rc::new(());
+            let accepted = inner_spawner.spawn(async move { listener.accept().await.is_ok() });
+            // In the reactor of the executor
+            let connected = TcpStream::connect(addr).await.is_ok();
+            timer.await;
+            let output = spawn_blocking(|| 42).await.unwrap();
+            (
+                connected,
+                accepted.await.unwrap(),
+                output,
+                Rc::strong_count(&local),
+            )
+        });
+        executor.run();
+
+        assert_eq!(
+            futures::executor::block_on(check).unwrap(),
+            (true, true, 42, 1)
+        );
+    }
+
+    #[test]
+    fn a_wakeup_from_a_foreign_thread_panics_the_executor() {
+        let (executor, spawner) = new_local_executor_and_spawner();
+        spawner.spawn(futures::future::poll_fn(|cx| {
+            let waker = cx.waker().clone();
+            // The waking thread itself doesn't panic
+            assert!(thread::spawn(move || waker.wake()).join().is_ok());
+            std::task::Poll::<()>::Pending
+        }));
+
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| executor.run())).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: counters and histograms of what an executor is doing, recorded by its tasks
+//       with relaxed atomics, so recording never blocks the executor
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Upper bounds of the duration buckets, in nanoseconds: from 1µs to 1s
+const DURATION_BOUNDS: &[u64] = &[
+    1_000,
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+];
+
+/// Upper bounds of the polls per task buckets
+const POLL_COUNT_BOUNDS: &[u64] = &[1, 2, 4, 8, 16, 32, 64, 128, 256, 512, 1024];
+
+/// Metrics shared by the spawners, the tasks and the executor
+pub(crate) struct Metrics {
+    /// Origin of the timestamps stored in the tasks, see `Metrics::now`
+    start: Instant,
+
+    spawned_tasks: AtomicU64,
+    polls: AtomicU64,
+    wakeups: AtomicU64,
+
+    /// How long a poll took
+    poll_duration: Histogram,
+
+    /// How long a task waited in the run queue before being polled
+    queue_delay: Histogram,
+
+    /// How many times a task was polled, recorded once it's complete
+    polls_per_task: Histogram,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Metrics {
+            start: Instant::now(),
+            spawned_tasks: AtomicU64::new(0),
+            polls: AtomicU64::new(0),
+            wakeups: AtomicU64::new(0),
+            poll_duration: Histogram::new(DURATION_BOUNDS),
+            queue_delay: Histogram::new(DURATION_BOUNDS),
+            polls_per_task: Histogram::new(POLL_COUNT_BOUNDS),
+        }
+    }
+
+    /// Nanoseconds since the metrics were created, cheap to store in an atomic
+    pub(crate) fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+
+    pub(crate) fn record_spawn(&self) {
+        self.spawned_tasks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_wakeup(&self) {
+        self.wakeups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a poll that started at `polled_at`, of a task queued at `scheduled_at`
+    pub(crate) fn record_poll(&self, scheduled_at: u64, polled_at: u64, duration: Duration) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.queue_delay
+            .record(polled_at.saturating_sub(scheduled_at));
+        self.poll_duration.record(duration.as_nanos() as u64);
+    }
+
+    pub(crate) fn record_completion(&self, polls: u64) {
+        self.polls_per_task.record(polls);
+    }
+
+    pub(crate) fn snapshot(&self, live_tasks: usize) -> MetricsSnapshot {
+        MetricsSnapshot {
+            spawned_tasks: self.spawned_tasks.load(Ordering::Relaxed),
+            live_tasks,
+            polls: self.polls.load(Ordering::Relaxed),
+            wakeups: self.wakeups.load(Ordering::Relaxed),
+            poll_duration: self.poll_duration.snapshot(),
+            queue_delay: self.queue_delay.snapshot(),
+            polls_per_task: self.polls_per_task.snapshot(),
+        }
+    }
+}
+
+/// A histogram with fixed buckets
+struct Histogram {
+    /// Inclusive upper bounds of the buckets, but the last one
+    bounds: &'static [u64],
+
+    /// Counts per bucket, the last one counts the values above every bound
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64]) -> Self {
+        Histogram {
+            bounds,
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, value: u64) {
+        let bucket = self.bounds.partition_point(|&bound| bound < value);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            bounds: self.bounds.to_vec(),
+            counts: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Metrics of an executor at a point in time, see `Executor::metrics`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub spawned_tasks: u64,
+
+    /// Tasks spawned but not complete yet
+    pub live_tasks: usize,
+
+    pub polls: u64,
+
+    /// Calls to the wakers of the tasks, including the ones
+    /// deduplicated since the task was queued already
+    pub wakeups: u64,
+
+    /// In nanoseconds
+    pub poll_duration: HistogramSnapshot,
+
+    /// Time spent in the run queue, in nanoseconds
+    pub queue_delay: HistogramSnapshot,
+
+    pub polls_per_task: HistogramSnapshot,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    /// Inclusive upper bounds of the buckets
+    pub bounds: Vec<u64>,
+
+    /// Counts per bucket, with one more bucket for the values above every bound
+    pub counts: Vec<u64>,
+
+    pub sum: u64,
+}
+
+impl HistogramSnapshot {
+    /// Number of values recorded
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    fn write_prometheus(&self, output: &mut String, name: &str, help: &str, scale: f64) {
+        writeln!(output, "# HELP {} {}", name, help).unwrap();
+        writeln!(output, "# TYPE {} histogram", name).unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            writeln!(
+                output,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                *bound as f64 / scale,
+                cumulative
+            )
+            .unwrap();
+        }
+        writeln!(output, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count()).unwrap();
+        writeln!(output, "{}_sum {}", name, self.sum as f64 / scale).unwrap();
+        writeln!(output, "{}_count {}", name, self.count()).unwrap();
+    }
+}
+
+impl MetricsSnapshot {
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut output = String::new();
+        let mut write_metric = |name: &str, kind: &str, help: &str, value: u64| {
+            writeln!(output, "# HELP {} {}", name, help).unwrap();
+            writeln!(output, "# TYPE {} {}", name, kind).unwrap();
+            writeln!(output, "{} {}", name, value).unwrap();
+        };
+        write_metric(
+            "executor_spawned_tasks_total",
+            "counter",
+            "Tasks spawned.",
+            self.spawned_tasks,
+        );
+        write_metric(
+            "executor_live_tasks",
+            "gauge",
+            "Tasks spawned but not complete yet.",
+            self.live_tasks as u64,
+        );
+        write_metric(
+            "executor_polls_total",
+            "counter",
+            "Polls of the tasks.",
+            self.polls,
+        );
+        write_metric(
+            "executor_wakeups_total",
+            "counter",
+            "Calls to the wakers of the tasks.",
+            self.wakeups,
+        );
+
+        self.poll_duration.write_prometheus(
+            &mut output,
+            "executor_poll_duration_seconds",
+            "Duration of a poll.",
+            1e9,
+        );
+        self.queue_delay.write_prometheus(
+            &mut output,
+            "executor_queue_delay_seconds",
+            "Time spent by a task in the run queue.",
+            1e9,
+        );
+        self.polls_per_task.write_prometheus(
+            &mut output,
+            "executor_polls_per_task",
+            "Polls of a task until its completion.",
+            1.0,
+        );
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::new_executor_and_spawner, timer_future::TimerFuture};
+
+    #[test]
+    fn counts_spawns_polls_and_wakeups() {
+        let (executor, spawner) = new_executor_and_spawner();
+        spawner.spawn(async {}).unwrap();
+        spawner
+            .spawn(async {
+                TimerFuture::new(Duration::from_millis(1)).await;
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        let metrics = executor.metrics();
+        assert_eq!(metrics.spawned_tasks, 2);
+        assert_eq!(metrics.live_tasks, 0);
+        assert_eq!(metrics.polls, 3);
+        assert_eq!(metrics.wakeups, 1);
+        assert_eq!(metrics.poll_duration.count(), 3);
+        assert_eq!(metrics.queue_delay.count(), 3);
+        assert_eq!(metrics.polls_per_task.counts[..2], [1, 1]);
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("executor_spawned_tasks_total 2\n"));
+        assert!(text.contains("executor_polls_per_task_bucket{le=\"2\"} 2\n"));
+        assert!(text.contains("executor_poll_duration_seconds_count 3\n"));
+    }
+}
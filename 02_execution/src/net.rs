@@ -0,0 +1,258 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: non-blocking TCP types on top of our reactor, so the 09. Final Project
+//       can run on our `Executor` instead of async-std
+
+use {
+    crate::reactor::{cvt, Interest, Registration},
+    futures::io::{AsyncRead, AsyncWrite},
+    std::{
+        future::Future,
+        io::{self, Read, Write},
+        mem,
+        net::{self, Shutdown, SocketAddr, ToSocketAddrs},
+        os::unix::io::{AsRawFd, FromRawFd},
+        pin::Pin,
+        ptr,
+        task::{Context, Poll},
+    },
+};
+
+/// A TCP socket server, listening for connections.
+pub struct TcpListener {
+    /// NOTE: declared first, so it's deregistered before the socket is closed
+    registration: Registration,
+    listener: net::TcpListener,
+}
+
+impl TcpListener {
+    /// Creates a listener bound to the `addr`, registered in the reactor
+    /// of the current thread.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let registration = Registration::new(listener.as_raw_fd(), Interest::READABLE)?;
+        Ok(TcpListener {
+            registration,
+            listener,
+        })
+    }
+
+    /// Accepts a new incoming connection.
+    pub fn accept(&self) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + '_ {
+        futures::future::poll_fn(move |cx| self.poll_accept(cx))
+    }
+
+    pub fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<io::Result<(TcpStream, SocketAddr)>> {
+        match self
+            .registration
+            .poll_io(cx, Interest::READABLE, || self.listener.accept())
+        {
+            Poll::Ready(Ok((stream, addr))) => {
+                Poll::Ready(TcpStream::new(stream).map(|s| (s, addr)))
+            }
+            Poll::Ready(Err(error)) => Poll::Ready(Err(error)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// A TCP stream between a local and a remote socket.
+pub struct TcpStream {
+    /// NOTE: declared first, so it's deregistered before the socket is closed
+    registration: Registration,
+    stream: net::TcpStream,
+}
+
+impl TcpStream {
+    /// Opens a TCP connection to the `addr`, without blocking the executor
+    /// while the connection is established.
+    ///
+    /// NOTE: it takes a resolved address, since resolving a host name blocks
+    ///       (it can be done with `spawn_blocking` beforehand)
+    pub async fn connect(addr: SocketAddr) -> io::Result<Self> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let flags = libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC;
+        // SAFETY: plain syscall, the result is checked
+        let fd = cvt(unsafe { libc::socket(domain, flags, 0) })?;
+        // SAFETY: `fd` is a new socket, owned by the stream from now on
+        let stream = unsafe { net::TcpStream::from_raw_fd(fd) };
+
+        let (storage, len) = to_sockaddr(addr);
+        // SAFETY: `storage` holds a valid address of `len` bytes
+        let connected = cvt(unsafe { libc::connect(fd, ptr::addr_of!(storage).cast(), len) });
+        match connected {
+            Err(error) if error.raw_os_error() != Some(libc::EINPROGRESS) => return Err(error),
+            // NOTE: the connection is usually in progress, even to a local address
+            _ => {}
+        }
+
+        // The socket is writable once the connection is established, or has failed
+        let stream = TcpStream::new(stream)?;
+        futures::future::poll_fn(|cx| stream.registration.poll_ready(cx, Interest::WRITABLE)).await;
+        match stream.stream.take_error()? {
+            Some(error) => Err(error),
+            None => Ok(stream),
+        }
+    }
+
+    fn new(stream: net::TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        let registration =
+            Registration::new(stream.as_raw_fd(), Interest::READABLE | Interest::WRITABLE)?;
+        Ok(TcpStream {
+            registration,
+            stream,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+}
+
+/// Converts the `addr` to a `sockaddr` for the libc calls, with its length
+fn to_sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: all zeros is a valid `sockaddr_storage`
+    let mut storage = unsafe { mem::zeroed::<libc::sockaddr_storage>() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sockaddr = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: addr.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from_ne_bytes(addr.ip().octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            // SAFETY: a `sockaddr_storage` is large and aligned enough for any address
+            unsafe { ptr::write(ptr::addr_of_mut!(storage).cast(), sockaddr) };
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sockaddr = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: addr.port().to_be(),
+                sin6_flowinfo: addr.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: addr.ip().octets(),
+                },
+                sin6_scope_id: addr.scope_id(),
+            };
+            // SAFETY: see above
+            unsafe { ptr::write(ptr::addr_of_mut!(storage).cast(), sockaddr) };
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // NOTE: `Read` is implemented for `&net::TcpStream` too,
+        //       so the stream isn't borrowed mutably alongside the registration
+        let mut stream = &this.stream;
+        this.registration
+            .poll_io(cx, Interest::READABLE, || stream.read(buf))
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut stream = &this.stream;
+        this.registration
+            .poll_io(cx, Interest::WRITABLE, || stream.write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Writes go straight to the socket, there is nothing buffered here
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.stream.shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::new_executor_and_spawner;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn echoes_over_a_connection() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let spawner_clone = spawner.clone();
+
+        let echoed = spawner
+            .spawn(async move {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let addr = listener.local_addr().unwrap();
+
+                let server = spawner_clone
+                    .spawn(async move {
+                        let (mut stream, _) = listener.accept().await.unwrap();
+                        let mut buffer = Vec::new();
+                        stream.read_to_end(&mut buffer).await.unwrap();
+                        stream.write_all(&buffer).await.unwrap();
+                    })
+                    .unwrap();
+
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                client.write_all(b"ping").await.unwrap();
+                client.close().await.unwrap();
+                let mut buffer = Vec::new();
+                client.read_to_end(&mut buffer).await.unwrap();
+                server.await.unwrap();
+                buffer
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(futures::executor::block_on(echoed).unwrap(), b"ping");
+    }
+
+    #[test]
+    fn connecting_to_a_closed_port_fails() {
+        let (executor, spawner) = new_executor_and_spawner();
+        // A port nobody listens on anymore
+        let addr = net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let refused = spawner
+            .spawn(async move { TcpStream::connect(addr).await.err().unwrap().kind() })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(
+            futures::executor::block_on(refused).unwrap(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+}
@@ -0,0 +1,446 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: the course only drives futures woken by other threads (like `TimerFuture`).
+//       A reactor waits on file descriptors instead: sockets, pipes etc. are registered
+//       in an epoll instance, and the `Executor` polls it when its ready queue is empty,
+//       waking the tasks whose file descriptors became ready
+
+use crate::{coop, local_executor};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    io, ops,
+    os::unix::io::RawFd,
+    sync::{Arc, Mutex, OnceLock},
+    task::{ready, Context, Poll, Waker},
+    thread,
+    time::Duration,
+};
+
+/// Readiness a `Registration` is interested in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interest(u8);
+
+impl Interest {
+    pub const READABLE: Interest = Interest(0b01);
+    pub const WRITABLE: Interest = Interest(0b10);
+
+    fn contains(self, other: Interest) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    fn to_epoll(self) -> u32 {
+        let mut events = libc::EPOLLET as u32;
+        if self.contains(Interest::READABLE) {
+            events |= (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+        }
+        if self.contains(Interest::WRITABLE) {
+            events |= libc::EPOLLOUT as u32;
+        }
+        events
+    }
+
+    fn from_epoll(events: u32) -> Self {
+        let mut readiness = Interest(0);
+        let events = events as i32;
+        if events & (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLHUP | libc::EPOLLERR) != 0 {
+            readiness = readiness | Interest::READABLE;
+        }
+        if events & (libc::EPOLLOUT | libc::EPOLLHUP | libc::EPOLLERR) != 0 {
+            readiness = readiness | Interest::WRITABLE;
+        }
+        readiness
+    }
+}
+
+impl ops::BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+}
+
+/// Readiness of a registered file descriptor, shared with the reactor
+struct IoState {
+    /// Readiness received since it was last cleared
+    readiness: Interest,
+
+    /// Incremented on every readiness event, so `clear_ready` doesn't
+    /// clear an event received after the operation that hit `WouldBlock`
+    tick: u64,
+
+    /// The wakers of the tasks waiting to read and to write
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+struct Sources {
+    /// Registrations by their epoll token
+    io_states: HashMap<u64, Arc<Mutex<IoState>>>,
+    next_token: u64,
+}
+
+struct Inner {
+    epoll_fd: RawFd,
+
+    /// Interrupts `epoll_wait`, see `Handle::unpark`
+    event_fd: RawFd,
+
+    sources: Mutex<Sources>,
+}
+
+/// The epoll token of the `event_fd`
+const UNPARK_TOKEN: u64 = u64::MAX;
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // SAFETY: both file descriptors are owned by the reactor
+        unsafe {
+            libc::close(self.event_fd);
+            libc::close(self.epoll_fd);
+        }
+    }
+}
+
+/// Handle to register file descriptors in a `Reactor`
+#[derive(Clone)]
+pub struct Handle {
+    inner: Arc<Inner>,
+}
+
+thread_local! {
+    /// Reactor of the executor running on the current thread
+    static CURRENT: RefCell<Option<Handle>> = const { RefCell::new(None) };
+}
+
+impl Handle {
+    /// Returns the handle of the reactor entered on the current thread,
+    /// or the one of a process-wide reactor, polled by a background thread,
+    /// if there is none (e.g. in a `ThreadPool`).
+    pub fn current() -> Self {
+        static DEFAULT: OnceLock<Handle> = OnceLock::new();
+
+        CURRENT
+            .with(|current| current.borrow().clone())
+            .unwrap_or_else(|| {
+                DEFAULT
+                    .get_or_init(|| {
+                        let reactor = Reactor::new();
+                        let handle = reactor.handle();
+                        thread::Builder::new()
+                            .name("reactor".into())
+                            .spawn(move || {
+                                local_executor::allow_local_wakeups();
+                                loop {
+                                    reactor.turn(None);
+                                }
+                            })
+                            .expect("failed to spawn the reactor thread");
+                        handle
+                    })
+                    .clone()
+            })
+    }
+
+    /// Interrupts the `Reactor::turn` in progress, or makes the next one return at once.
+    pub(crate) fn unpark(&self) {
+        let one = 1u64.to_ne_bytes();
+        // NOTE: it only fails if the counter is about to overflow,
+        //       and then the reactor is woken up anyway
+        // SAFETY: writes 8 bytes from a valid buffer
+        unsafe { libc::write(self.inner.event_fd, one.as_ptr().cast(), one.len()) };
+    }
+
+    fn register(&self, fd: RawFd, interest: Interest) -> io::Result<(u64, Arc<Mutex<IoState>>)> {
+        let io_state = Arc::new(Mutex::new(IoState {
+            readiness: Interest(0),
+            tick: 0,
+            read_waker: None,
+            write_waker: None,
+        }));
+
+        let mut sources = self.inner.sources.lock().unwrap();
+        let token = sources.next_token;
+        let mut event = libc::epoll_event {
+            events: interest.to_epoll(),
+            u64: token,
+        };
+        // SAFETY: `event` is a valid `epoll_event`
+        cvt(unsafe { libc::epoll_ctl(self.inner.epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event) })?;
+        sources.next_token += 1;
+        sources.io_states.insert(token, io_state.clone());
+
+        Ok((token, io_state))
+    }
+
+    fn deregister(&self, fd: RawFd, token: u64) {
+        self.inner.sources.lock().unwrap().io_states.remove(&token);
+        // NOTE: fails if the file descriptor is closed already,
+        //       which removes it from the epoll instance too
+        // SAFETY: a null event is allowed for `EPOLL_CTL_DEL`
+        unsafe {
+            libc::epoll_ctl(
+                self.inner.epoll_fd,
+                libc::EPOLL_CTL_DEL,
+                fd,
+                std::ptr::null_mut(),
+            )
+        };
+    }
+}
+
+/// An I/O reactor, waking the tasks waiting on registered file descriptors
+/// when they become ready.
+pub struct Reactor {
+    handle: Handle,
+}
+
+impl Reactor {
+    pub fn new() -> Self {
+        // SAFETY: plain syscalls, the results are checked
+        let epoll_fd = cvt(unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) })
+            .expect("failed to create an epoll instance");
+        let event_fd = cvt(unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) })
+            .expect("failed to create an eventfd");
+        let inner = Arc::new(Inner {
+            epoll_fd,
+            event_fd,
+            sources: Mutex::new(Sources {
+                io_states: HashMap::new(),
+                next_token: 0,
+            }),
+        });
+
+        // NOTE: level-triggered, so an `unpark` before `turn` isn't lost
+        let mut event = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: UNPARK_TOKEN,
+        };
+        // SAFETY: `event` is a valid `epoll_event`
+        cvt(unsafe { libc::epoll_ctl(epoll_fd, libc::EPOLL_CTL_ADD, event_fd, &mut event) })
+            .expect("failed to register the eventfd");
+
+        Reactor {
+            handle: Handle { inner },
+        }
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Makes `Registration`s created on the current thread register in this reactor
+    /// until the returned guard is dropped.
+    pub fn enter(&self) -> EnterGuard {
+        let previous = CURRENT.with(|current| current.replace(Some(self.handle())));
+        EnterGuard { previous }
+    }
+
+    /// Waits for readiness events, at most for `timeout` (forever if `None`),
+    /// or until `Handle::unpark` is called, and wakes the tasks waiting for them.
+    pub fn turn(&self, timeout: Option<Duration>) {
+        let inner = &self.handle.inner;
+        let timeout = match timeout {
+            // Rounded up, so we don't spin for the last fraction of a millisecond
+            Some(timeout) => timeout
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .try_into()
+                .unwrap_or(i32::MAX),
+            None => -1,
+        };
+
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; 64];
+        // SAFETY: `events` is a valid buffer of `events.len()` entries
+        let count = unsafe {
+            libc::epoll_wait(
+                inner.epoll_fd,
+                events.as_mut_ptr(),
+                events.len() as i32,
+                timeout,
+            )
+        };
+        // NOTE: only `EINTR` is expected here, the caller turns again anyway
+        let count = usize::try_from(count).unwrap_or(0);
+
+        let mut wakers = Vec::new();
+        let sources = inner.sources.lock().unwrap();
+        for event in &events[..count] {
+            let token = event.u64;
+            if token == UNPARK_TOKEN {
+                let mut buffer = [0u8; 8];
+                // SAFETY: reads 8 bytes into a valid buffer
+                unsafe { libc::read(inner.event_fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+                continue;
+            }
+            // Skip the events of registrations dropped in the meantime
+            if let Some(io_state) = sources.io_states.get(&token) {
+                let readiness = Interest::from_epoll(event.events);
+                let mut io_state = io_state.lock().unwrap();
+                io_state.readiness = io_state.readiness | readiness;
+                io_state.tick += 1;
+                if readiness.contains(Interest::READABLE) {
+                    wakers.extend(io_state.read_waker.take());
+                }
+                if readiness.contains(Interest::WRITABLE) {
+                    wakers.extend(io_state.write_waker.take());
+                }
+            }
+        }
+        drop(sources);
+
+        // NOTE: wakers are called without holding the lock, see `TimerDriver`
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+impl Default for Reactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Restores the previously entered reactor on drop
+pub struct EnterGuard {
+    previous: Option<Handle>,
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+/// A file descriptor registered in the reactor of the current thread.
+///
+/// The file descriptor should be non-blocking, and must outlive the registration.
+pub struct Registration {
+    handle: Handle,
+    fd: RawFd,
+    token: u64,
+    io_state: Arc<Mutex<IoState>>,
+}
+
+impl Registration {
+    pub fn new(fd: RawFd, interest: Interest) -> io::Result<Self> {
+        let handle = Handle::current();
+        let (token, io_state) = handle.register(fd, interest)?;
+        Ok(Registration {
+            handle,
+            fd,
+            token,
+            io_state,
+        })
+    }
+
+    /// Returns the tick of the readiness event, once the file descriptor
+    /// is ready for the `direction` (either `READABLE` or `WRITABLE`).
+    pub fn poll_ready(&self, cx: &mut Context<'_>, direction: Interest) -> Poll<u64> {
+        let mut io_state = self.io_state.lock().unwrap();
+        if io_state.readiness.contains(direction) {
+            return Poll::Ready(io_state.tick);
+        }
+
+        let waker = if direction == Interest::READABLE {
+            &mut io_state.read_waker
+        } else {
+            &mut io_state.write_waker
+        };
+        *waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Clears the readiness for the `direction`, after an operation hit `WouldBlock`,
+    /// unless a newer readiness event than the `tick` was received.
+    pub fn clear_ready(&self, direction: Interest, tick: u64) {
+        let mut io_state = self.io_state.lock().unwrap();
+        if io_state.tick == tick {
+            io_state.readiness = Interest(io_state.readiness.0 & !direction.0);
+        }
+    }
+
+    /// Runs the non-blocking `operation` once the file descriptor is ready for
+    /// the `direction`, until it doesn't fail with `WouldBlock`.
+    pub fn poll_io<R>(
+        &self,
+        cx: &mut Context<'_>,
+        direction: Interest,
+        mut operation: impl FnMut() -> io::Result<R>,
+    ) -> Poll<io::Result<R>> {
+        ready!(coop::poll_proceed(cx));
+
+        loop {
+            let tick = match self.poll_ready(cx, direction) {
+                Poll::Ready(tick) => tick,
+                Poll::Pending => return Poll::Pending,
+            };
+            match operation() {
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    self.clear_ready(direction, tick)
+                }
+                result => return Poll::Ready(result),
+            }
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.handle.deregister(self.fd, self.token);
+    }
+}
+
+/// Turns the `-1` of a failed syscall into the last OS error
+pub(crate) fn cvt(result: i32) -> io::Result<i32> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::new_executor_and_spawner;
+    use std::{
+        io::{Read, Write},
+        os::unix::{io::AsRawFd, net::UnixStream},
+    };
+
+    #[test]
+    fn wakes_the_task_waiting_on_a_socket() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let (mut reader, mut writer) = UnixStream::pair().unwrap();
+        reader.set_nonblocking(true).unwrap();
+
+        let received = spawner
+            .spawn(async move {
+                let registration =
+                    Registration::new(reader.as_raw_fd(), Interest::READABLE).unwrap();
+                let mut buffer = [0; 5];
+                let read = futures::future::poll_fn(|cx| {
+                    registration.poll_io(cx, Interest::READABLE, || reader.read(&mut buffer))
+                })
+                .await
+                .unwrap();
+                drop(registration);
+                buffer[..read].to_vec()
+            })
+            .unwrap();
+        drop(spawner);
+
+        // The executor has nothing to run but the reactor until then
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            writer.write_all(b"hello").unwrap();
+        });
+        executor.run();
+        writer.join().unwrap();
+
+        assert_eq!(futures::executor::block_on(received).unwrap(), b"hello");
+    }
+}
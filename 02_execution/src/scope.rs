@@ -0,0 +1,272 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: structured concurrency: the children spawned in a scope can't outlive it, so they
+//       can borrow the data of the task opening the scope. They run concurrently within
+//       the future returned by `scope`, not as separate tasks of the executor: a task
+//       could outlive the scope if its future was leaked (e.g. with `mem::forget`),
+//       while children owned by the scope future are dropped along with it
+
+use {
+    futures::{
+        future::BoxFuture,
+        stream::{FuturesUnordered, StreamExt},
+    },
+    std::{
+        future::Future,
+        mem,
+        sync::{Arc, Mutex},
+        task::{Poll, Waker},
+    },
+};
+
+/// Handle to spawn children in a scope, see `scope`
+pub struct Scope<'env, E> {
+    shared: Arc<Mutex<Spawned<'env, E>>>,
+}
+
+/// Children spawned since the scope was last polled
+struct Spawned<'env, E> {
+    futures: Vec<BoxFuture<'env, Result<(), E>>>,
+
+    /// Of the task polling the scope
+    waker: Option<Waker>,
+}
+
+impl<E> Clone for Scope<'_, E> {
+    fn clone(&self) -> Self {
+        Scope {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<'env, E> Scope<'env, E> {
+    /// Spawns a child running concurrently with the scope body and the other children.
+    ///
+    /// An error returned by the child cancels the scope, see `scope`.
+    pub fn spawn(&self, future: impl Future<Output = Result<(), E>> + Send + 'env) {
+        let mut spawned = self.shared.lock().unwrap();
+        spawned.futures.push(Box::pin(future));
+        let waker = spawned.waker.clone();
+        drop(spawned);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Drops the children spawned but not polled yet
+    ///
+    /// NOTE: they may hold a `Scope` themselves, so they would keep each other
+    ///       alive forever (and never run their destructors) otherwise
+    fn cancel(&self) {
+        let mut spawned = self.shared.lock().unwrap();
+        let futures = mem::take(&mut spawned.futures);
+        spawned.waker = None;
+        drop(spawned);
+
+        drop(futures);
+    }
+}
+
+/// Cancels the children spawned but not polled yet on drop, see `Scope::cancel`
+struct CancelOnDrop<'a, 'env, E>(&'a Scope<'env, E>);
+
+impl<E> Drop for CancelOnDrop<'_, '_, E> {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// Runs the body returned by `f`, and the children it spawns with the given `Scope`,
+/// until they are all complete.
+///
+/// The first error returned by a child cancels the scope: the body and the other
+/// children are dropped, and the error is returned. A panic of the body or a child
+/// unwinds through the scope, the same way.
+pub async fn scope<'env, T, E, F>(f: impl FnOnce(Scope<'env, E>) -> F) -> Result<T, E>
+where
+    F: Future<Output = T> + Send + 'env,
+    E: Send + 'env,
+{
+    let scope = Scope {
+        shared: Arc::new(Mutex::new(Spawned {
+            futures: Vec::new(),
+            waker: None,
+        })),
+    };
+    // Whether the scope completes, fails or is dropped before completion
+    let _cancel = CancelOnDrop(&scope);
+    let mut body = Box::pin(f(scope.clone()));
+    let mut output = None;
+    let mut children = FuturesUnordered::new();
+
+    futures::future::poll_fn(|cx| {
+        scope.shared.lock().unwrap().waker = Some(cx.waker().clone());
+
+        if output.is_none() {
+            if let Poll::Ready(value) = body.as_mut().poll(cx) {
+                output = Some(value);
+            }
+        }
+
+        loop {
+            children.extend(mem::take(&mut scope.shared.lock().unwrap().futures));
+            match children.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(()))) => {}
+                Poll::Ready(Some(Err(error))) => {
+                    // Cancels the other children right away,
+                    // even the ones spawned during this poll
+                    children.clear();
+                    scope.cancel();
+                    return Poll::Ready(Err(error));
+                }
+                // NOTE: unless the children polled just now spawned new ones
+                Poll::Ready(None) | Poll::Pending
+                    if scope.shared.lock().unwrap().futures.is_empty() =>
+                {
+                    break
+                }
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+        }
+
+        match output.take() {
+            Some(value) if children.is_empty() => Poll::Ready(Ok(value)),
+            value => {
+                output = value;
+                Poll::Pending
+            }
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::new_executor_and_spawner, simulation::new_simulation_and_spawner,
+        timer_future::TimerFuture,
+    };
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    #[test]
+    fn children_borrow_the_data_of_the_scope() {
+        let (executor, spawner) = new_executor_and_spawner();
+
+        let total = spawner
+            .spawn(async {
+                let values = vec![1, 2, 3, 4];
+                let total = AtomicUsize::new(0);
+
+                let (values, total_ref) = (&values, &total);
+                let result = scope(|s| async move {
+                    for value in values {
+                        let child_scope = s.clone();
+                        s.spawn(async move {
+                            TimerFuture::new(Duration::from_millis(*value as u64)).await;
+                            total_ref.fetch_add(*value, Ordering::SeqCst);
+                            // Children can spawn children too
+                            child_scope.spawn(async move {
+                                total_ref.fetch_add(*value, Ordering::SeqCst);
+                                Ok::<_, ()>(())
+                            });
+                            Ok(())
+                        });
+                    }
+                    "body"
+                })
+                .await;
+
+                // Every child is complete once the scope is
+                (result, total.load(Ordering::SeqCst))
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        let total = futures::executor::block_on(total).unwrap();
+        assert_eq!(total, (Ok("body"), 20));
+    }
+
+    struct SetOnDrop<'a>(&'a AtomicBool);
+
+    impl Drop for SetOnDrop<'_> {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn the_first_error_cancels_the_other_children() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let result = spawner
+            .spawn(async {
+                let cancelled = AtomicBool::new(false);
+
+                let result = scope(|s| {
+                    let cancelled = &cancelled;
+                    s.spawn(async move {
+                        let _guard = SetOnDrop(cancelled);
+                        futures::future::pending::<()>().await;
+                        Ok(())
+                    });
+                    s.spawn(async {
+                        TimerFuture::new(Duration::from_millis(5)).await;
+                        Err("failed")
+                    });
+                    async { futures::future::pending::<()>().await }
+                })
+                .await;
+
+                (result, cancelled.load(Ordering::SeqCst))
+            })
+            .unwrap();
+        drop(spawner);
+        executor.run();
+
+        let result = futures::executor::block_on(result).unwrap();
+        assert_eq!(result, (Err("failed"), true));
+    }
+
+    #[test]
+    fn children_spawned_by_a_failing_child_are_cancelled_too() {
+        // NOTE: a simulation, since a leaked child would keep the waker
+        //       of the task alive, and so an `Executor` from returning
+        let (mut simulation, spawner) = new_simulation_and_spawner(0);
+        let result = spawner
+            .spawn(async {
+                let cancelled = AtomicBool::new(false);
+
+                let cancelled_ref = &cancelled;
+                let result = scope(|s| {
+                    let child_scope = s.clone();
+                    s.spawn(async move {
+                        // Spawned during the poll returning the error,
+                        // with a `Scope` of its own
+                        let grandchild_scope = child_scope.clone();
+                        let guard = SetOnDrop(cancelled_ref);
+                        child_scope.spawn(async move {
+                            let _held = (grandchild_scope, guard);
+                            futures::future::pending::<()>().await;
+                            Ok(())
+                        });
+                        Err("failed")
+                    });
+                    async {}
+                })
+                .await;
+
+                (result, cancelled.load(Ordering::SeqCst))
+            })
+            .unwrap();
+        simulation.run();
+
+        let result = futures::executor::block_on(result).unwrap();
+        assert_eq!(result, (Err("failed"), true));
+    }
+}
@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: a deterministic variant of the `Executor` from 2.3. Applied: Build an Executor,
+//       to reproduce concurrency bugs. The next task to poll is picked at random,
+//       from a seeded generator, and the `TimerFuture`s run on a virtual clock which
+//       jumps to the next deadline once no task is ready. So a run only depends
+//       on its seed, and a failing seed can be replayed.
+//
+//       Only our timers (and `spawn_blocking`, run inline) are simulated: a task woken
+//       by a thread of its own (or by I/O) makes the run nondeterministic again,
+//       mocks should be used instead
+
+use {
+    crate::{
+        blocking::BlockingPool,
+        executor::{Hooks, Schedule, Spawner, TaskRef, TaskRegistry},
+        timer_future::TimerDriver,
+    },
+    std::{
+        env,
+        ops::Range,
+        panic::{self, AssertUnwindSafe},
+        sync::{Arc, Mutex},
+    },
+};
+
+/// Environment variable to replay a single seed with `check`
+pub const SEED_VAR: &str = "SIMULATION_SEED";
+
+/// Single-threaded executor polling its tasks in a seeded random order.
+pub struct Simulation {
+    seed: u64,
+
+    /// State of the random generator
+    rng: u64,
+
+    scheduler: Arc<Scheduler>,
+
+    /// Fires the `TimerFuture`s created by the tasks, with a virtual clock
+    timer: TimerDriver,
+
+    /// Runs the closures given to `spawn_blocking` right away, on the simulation thread
+    blocking: BlockingPool,
+
+    hooks: Hooks,
+}
+
+/// Collects the woken tasks, in no particular order
+struct Scheduler {
+    ready: Mutex<Vec<TaskRef>>,
+}
+
+impl Schedule for Scheduler {
+    fn schedule(&self, task: TaskRef) {
+        self.ready.lock().unwrap().push(task);
+    }
+}
+
+pub fn new_simulation_and_spawner(seed: u64) -> (Simulation, Spawner) {
+    let scheduler = Arc::new(Scheduler {
+        ready: Mutex::new(Vec::new()),
+    });
+    let simulation = Simulation {
+        seed,
+        rng: seed,
+        scheduler: scheduler.clone(),
+        timer: TimerDriver::new_virtual(),
+        blocking: BlockingPool::new_inline(),
+        hooks: Hooks::default(),
+    };
+    let spawner = Spawner::new(scheduler, Arc::new(TaskRegistry::new()));
+    (simulation, spawner)
+}
+
+impl Simulation {
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Runs the tasks until none is ready and no timer is left.
+    ///
+    /// NOTE: unlike `Executor::run`, it doesn't wait for the spawners to be dropped:
+    ///       nothing can wake a task but another task or a timer
+    pub fn run(&mut self) {
+        // Make the `TimerFuture`s created while polling register in our virtual clock
+        let _timer = self.timer.enter();
+        // ... and `spawn_blocking` doesn't wake the tasks from another thread
+        let _blocking = self.blocking.enter();
+
+        loop {
+            let task = {
+                let mut ready = self.scheduler.ready.lock().unwrap();
+                match ready.len() {
+                    0 => None,
+                    len => Some(ready.swap_remove(next_random(&mut self.rng) as usize % len)),
+                }
+            };
+            match task {
+                Some(task) => task.run(&self.hooks),
+                None if self.timer.advance_to_next_deadline() => {}
+                None => return,
+            }
+        }
+    }
+}
+
+/// SplitMix64, good enough to shuffle tasks, and reproducible
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Runs the `test` once per seed, to explore the interleavings of its tasks.
+///
+/// The seed of a failing run is printed, and can be replayed alone by setting
+/// the `SIMULATION_SEED` environment variable.
+pub fn check(seeds: Range<u64>, test: impl Fn(u64)) {
+    let seeds = match env::var(SEED_VAR) {
+        Ok(seed) => {
+            let seed = seed
+                .parse::<u64>()
+                .unwrap_or_else(|_| panic!("{} must be a number", SEED_VAR));
+            seed..seed + 1
+        }
+        Err(_) => seeds,
+    };
+
+    for seed in seeds {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| test(seed))) {
+            eprintln!(
+                "simulation failed with seed {0}, replay it with {1}={0}",
+                seed, SEED_VAR
+            );
+            panic::resume_unwind(payload);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timer_future::TimerFuture;
+    use std::time::{Duration, Instant};
+
+    /// Returns the order the tasks ran in, with the seed
+    fn interleaving(seed: u64) -> Vec<u32> {
+        let (mut simulation, spawner) = new_simulation_and_spawner(seed);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..8 {
+            let order = order.clone();
+            spawner
+                .spawn(async move {
+                    order.lock().unwrap().push(i);
+                    // A day, but the virtual clock jumps there at once
+                    TimerFuture::new(Duration::from_secs(24 * 60 * 60)).await;
+                    order.lock().unwrap().push(10 + i);
+                })
+                .unwrap();
+        }
+        simulation.run();
+
+        let order = order.lock().unwrap().clone();
+        order
+    }
+
+    #[test]
+    fn runs_are_reproducible_from_their_seed() {
+        let start = Instant::now();
+        check(0..16, |seed| {
+            assert_eq!(interleaving(seed), interleaving(seed))
+        });
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Other seeds explore other interleavings
+        assert!((1..16).any(|seed| interleaving(seed) != interleaving(0)));
+    }
+}
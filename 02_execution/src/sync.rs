@@ -0,0 +1,20 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: synchronization primitives for the tasks of our executors. Unlike `std::sync`,
+//       waiting parks the task with its `Waker` instead of blocking the executor thread.
+//       The waiters are served in FIFO order, and a waiting future can be dropped
+//       at any time without losing what it was given (e.g. a permit or a notification)
+
+mod barrier;
+mod mutex;
+mod notify;
+mod rwlock;
+mod semaphore;
+
+pub use self::{
+    barrier::{Barrier, BarrierWait, BarrierWaitResult},
+    mutex::{Mutex, MutexGuard},
+    notify::{Notified, Notify},
+    rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard},
+    semaphore::{Acquire, Semaphore, SemaphorePermit},
+};
@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    crate::coop,
+    std::{
+        future::Future,
+        mem,
+        pin::Pin,
+        sync::Mutex,
+        task::{ready, Context, Poll, Waker},
+    },
+};
+
+/// Makes a number of tasks wait for each other, then releases them all at once.
+///
+/// The barrier can be reused once they are released.
+pub struct Barrier {
+    parties: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Tasks waiting in the current generation
+    arrived: usize,
+
+    /// Incremented each time the tasks are released
+    generation: u64,
+
+    wakers: Vec<Waker>,
+}
+
+/// Returned to each task released from a `Barrier`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// Whether this task was the last to arrive, a single task of each generation is
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+}
+
+impl Barrier {
+    /// Creates a barrier releasing the tasks once `parties` of them wait.
+    pub fn new(parties: usize) -> Self {
+        Barrier {
+            parties: parties.max(1),
+            state: Mutex::new(State {
+                arrived: 0,
+                generation: 0,
+                wakers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Waits for the other tasks.
+    pub fn wait(&self) -> BarrierWait<'_> {
+        BarrierWait {
+            barrier: self,
+            generation: None,
+        }
+    }
+}
+
+/// Future returned by `Barrier::wait`
+pub struct BarrierWait<'a> {
+    barrier: &'a Barrier,
+
+    /// Generation the task arrived in, while it waits
+    generation: Option<u64>,
+}
+
+impl Future for BarrierWait<'_> {
+    type Output = BarrierWaitResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(coop::poll_proceed(cx));
+
+        let this = self.get_mut();
+        let mut state = this.barrier.state.lock().unwrap();
+        match this.generation {
+            Some(generation) if generation != state.generation => {
+                this.generation = None;
+                Poll::Ready(BarrierWaitResult { leader: false })
+            }
+            Some(_) => {
+                if !state.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                    state.wakers.push(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+            None if state.arrived + 1 == this.barrier.parties => {
+                state.arrived = 0;
+                state.generation += 1;
+                let wakers = mem::take(&mut state.wakers);
+                drop(state);
+
+                wakers.into_iter().for_each(Waker::wake);
+                Poll::Ready(BarrierWaitResult { leader: true })
+            }
+            None => {
+                state.arrived += 1;
+                state.wakers.push(cx.waker().clone());
+                this.generation = Some(state.generation);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for BarrierWait<'_> {
+    fn drop(&mut self) {
+        // NOTE: a task leaving before the release doesn't count anymore
+        //       (its waker stays, to be woken spuriously)
+        if let Some(generation) = self.generation {
+            let mut state = self.barrier.state.lock().unwrap();
+            if state.generation == generation {
+                state.arrived -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::new_executor_and_spawner;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[test]
+    fn releases_the_tasks_once_all_of_them_wait() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let barrier = Arc::new(Barrier::new(3));
+        let arrived = Arc::new(AtomicUsize::new(0));
+
+        let leaders = (0..3)
+            .map(|_| {
+                let barrier = barrier.clone();
+                let arrived = arrived.clone();
+                spawner
+                    .spawn(async move {
+                        let mut leaders = 0;
+                        // Reused for a few generations
+                        for generation in 0..4 {
+                            arrived.fetch_add(1, Ordering::SeqCst);
+                            let result = barrier.wait().await;
+                            assert!(arrived.load(Ordering::SeqCst) >= 3 * (generation + 1));
+                            leaders += result.is_leader() as usize;
+                        }
+                        leaders
+                    })
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        drop(spawner);
+        executor.run();
+
+        let leaders = futures::executor::block_on(futures::future::join_all(leaders));
+        let leaders = leaders.into_iter().map(Result::unwrap).sum::<usize>();
+        assert_eq!(leaders, 4);
+    }
+}
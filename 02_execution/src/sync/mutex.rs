@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    super::{Semaphore, SemaphorePermit},
+    std::{
+        cell::UnsafeCell,
+        ops::{Deref, DerefMut},
+    },
+};
+
+/// A mutex which can be held across `.await`s, on top of a `Semaphore` with a single permit.
+pub struct Mutex<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the value is only accessed through a guard, which holds the single permit
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Mutex {
+            semaphore: Semaphore::new(1),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Waits for the lock, after the tasks which asked for it first.
+    pub async fn lock(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            _permit: self.semaphore.acquire().await,
+            mutex: self,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        Some(MutexGuard {
+            _permit: self.semaphore.try_acquire()?,
+            mutex: self,
+        })
+    }
+
+    /// No lock is needed, since the mutex is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+/// Releases the lock on drop
+pub struct MutexGuard<'a, T: ?Sized> {
+    _permit: SemaphorePermit<'a>,
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard holds the single permit
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard holds the single permit
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::new_executor_and_spawner, timer_future::TimerFuture};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn the_lock_is_held_across_awaits() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let mutex = Arc::new(Mutex::new(Vec::new()));
+
+        for id in 0..4 {
+            let mutex = mutex.clone();
+            spawner
+                .spawn(async move {
+                    let mut entries = mutex.lock().await;
+                    entries.push((id, "start"));
+                    // Would let the other tasks interleave if the lock was released
+                    TimerFuture::new(Duration::from_millis(5)).await;
+                    entries.push((id, "end"));
+                })
+                .unwrap();
+        }
+        drop(spawner);
+        executor.run();
+
+        let entries = Arc::try_unwrap(mutex).ok().unwrap().into_inner();
+        let expected = (0..4)
+            .flat_map(|id| vec![(id, "start"), (id, "end")])
+            .collect::<Vec<_>>();
+        // In the order the tasks asked for the lock
+        assert_eq!(entries, expected);
+    }
+}
@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    crate::coop,
+    std::{
+        collections::{HashMap, VecDeque},
+        future::Future,
+        pin::Pin,
+        sync::Mutex,
+        task::{ready, Context, Poll, Waker},
+    },
+};
+
+/// Notifies the waiting tasks of an event, without carrying any data.
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Set by `notify_one` when nobody waits, consumed by the next waiter
+    permit: bool,
+
+    /// Ids of the waiters not notified yet, the first one is notified first
+    queue: VecDeque<u64>,
+
+    /// Waiters by id, until their `Notified` future completes (or is dropped)
+    waiters: HashMap<u64, Waiter>,
+
+    next_id: u64,
+}
+
+struct Waiter {
+    notified: Option<Notification>,
+    waker: Option<Waker>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+impl State {
+    /// Notifies the first waiter, or stores a permit if nobody waits
+    fn notify_one(&mut self) -> Option<Waker> {
+        match self.queue.pop_front() {
+            Some(id) => {
+                let waiter = self.waiters.get_mut(&id).unwrap();
+                waiter.notified = Some(Notification::One);
+                waiter.waker.take()
+            }
+            None => {
+                self.permit = true;
+                None
+            }
+        }
+    }
+}
+
+impl Notify {
+    pub fn new() -> Self {
+        Notify {
+            state: Mutex::new(State {
+                permit: false,
+                queue: VecDeque::new(),
+                waiters: HashMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Notifies the task waiting for the longest time, or the next one to wait
+    /// if there is none.
+    pub fn notify_one(&self) {
+        let waker = self.state.lock().unwrap().notify_one();
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Notifies every task waiting at the moment, no permit is stored.
+    pub fn notify_waiters(&self) {
+        let mut state = self.state.lock().unwrap();
+        let queue = std::mem::take(&mut state.queue);
+        let wakers = queue
+            .into_iter()
+            .filter_map(|id| {
+                let waiter = state.waiters.get_mut(&id).unwrap();
+                waiter.notified = Some(Notification::All);
+                waiter.waker.take()
+            })
+            .collect::<Vec<_>>();
+        drop(state);
+
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Waits for a notification.
+    ///
+    /// NOTE: the future starts waiting once it's first polled,
+    ///       the notifications sent before are missed (but the permit)
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Future returned by `Notify::notified`
+pub struct Notified<'a> {
+    notify: &'a Notify,
+
+    /// Id of the waiter, once queued
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(coop::poll_proceed(cx));
+
+        let this = self.get_mut();
+        let mut state = this.notify.state.lock().unwrap();
+        match this.id {
+            None if state.permit => state.permit = false,
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.insert(
+                    id,
+                    Waiter {
+                        notified: None,
+                        waker: Some(cx.waker().clone()),
+                    },
+                );
+                state.queue.push_back(id);
+                this.id = Some(id);
+                return Poll::Pending;
+            }
+            Some(id) => {
+                let waiter = state.waiters.get_mut(&id).unwrap();
+                if waiter.notified.is_none() {
+                    waiter.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                state.waiters.remove(&id);
+                this.id = None;
+            }
+        }
+        Poll::Ready(())
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let mut state = self.notify.state.lock().unwrap();
+        let waker = match state.waiters.remove(&id).unwrap().notified {
+            // NOTE: the notification was meant for a single task, so it's passed on
+            Some(Notification::One) => state.notify_one(),
+            Some(Notification::All) => None,
+            None => {
+                state.queue.retain(|&queued| queued != id);
+                None
+            }
+        };
+        drop(state);
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, poll};
+
+    #[test]
+    fn a_notification_is_passed_on_if_its_waiter_is_dropped() {
+        let notify = Notify::new();
+        block_on(async {
+            // Stored for the next waiter
+            notify.notify_one();
+            notify.notified().await;
+
+            let mut first = Box::pin(notify.notified());
+            let mut second = Box::pin(notify.notified());
+            assert!(poll!(first.as_mut()).is_pending());
+            assert!(poll!(second.as_mut()).is_pending());
+
+            notify.notify_one();
+            assert!(poll!(second.as_mut()).is_pending());
+            drop(first);
+            second.await;
+
+            let mut waiters = (0..3)
+                .map(|_| Box::pin(notify.notified()))
+                .collect::<Vec<_>>();
+            for waiter in &mut waiters {
+                assert!(poll!(waiter.as_mut()).is_pending());
+            }
+            notify.notify_waiters();
+            futures::future::join_all(waiters).await;
+        });
+        assert!(!notify.state.lock().unwrap().permit);
+    }
+}
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    super::{Semaphore, SemaphorePermit},
+    std::{
+        cell::UnsafeCell,
+        ops::{Deref, DerefMut},
+    },
+};
+
+/// Maximum number of concurrent readers: a writer acquires all the permits at once
+const MAX_READERS: usize = 1 << 16;
+
+/// A reader-writer lock which can be held across `.await`s, on top of a `Semaphore`.
+///
+/// NOTE: the readers and the writers are served in a single FIFO queue, so a waiting
+///       writer holds the next readers back, and can't be starved by them
+pub struct RwLock<T: ?Sized> {
+    semaphore: Semaphore,
+    value: UnsafeCell<T>,
+}
+
+// SAFETY: the value is shared by the read guards (so `T: Sync`),
+//         and mutated by a single write guard (so `T: Send`)
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+impl<T> RwLock<T> {
+    pub fn new(value: T) -> Self {
+        RwLock {
+            semaphore: Semaphore::new(MAX_READERS),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Waits for a shared access, along with the other readers.
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        RwLockReadGuard {
+            _permit: self.semaphore.acquire().await,
+            lock: self,
+        }
+    }
+
+    /// Waits for an exclusive access.
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        RwLockWriteGuard {
+            _permit: self.semaphore.acquire_many(MAX_READERS).await,
+            lock: self,
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        Some(RwLockReadGuard {
+            _permit: self.semaphore.try_acquire()?,
+            lock: self,
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        Some(RwLockWriteGuard {
+            _permit: self.semaphore.try_acquire_many(MAX_READERS)?,
+            lock: self,
+        })
+    }
+
+    /// No lock is needed, since the lock is borrowed mutably.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    _permit: SemaphorePermit<'a>,
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: no writer holds the permits while a reader holds one
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    _permit: SemaphorePermit<'a>,
+    lock: &'a RwLock<T>,
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the guard holds all the permits
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: the guard holds all the permits
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, poll};
+
+    #[test]
+    fn a_waiting_writer_holds_the_next_readers_back() {
+        let lock = RwLock::new(0);
+        block_on(async {
+            let first = lock.read().await;
+            let second = lock.read().await;
+
+            let mut write = Box::pin(lock.write());
+            assert!(poll!(write.as_mut()).is_pending());
+            assert!(lock.try_read().is_none());
+
+            drop((first, second));
+            *write.await += 1;
+            assert_eq!(*lock.read().await, 1);
+        });
+    }
+}
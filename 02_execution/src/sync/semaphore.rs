@@ -0,0 +1,248 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use {
+    crate::coop,
+    std::{
+        collections::{HashMap, VecDeque},
+        future::Future,
+        pin::Pin,
+        sync::Mutex,
+        task::{ready, Context, Poll, Waker},
+    },
+};
+
+/// A counting semaphore, granting its permits to the waiting tasks in FIFO order.
+pub struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Permits neither acquired nor granted to a waiter
+    permits: usize,
+
+    /// Ids of the waiters not granted yet, the first one is served first
+    queue: VecDeque<u64>,
+
+    /// Waiters by id, until their `Acquire` future takes the permits (or is dropped)
+    waiters: HashMap<u64, Waiter>,
+
+    next_id: u64,
+}
+
+struct Waiter {
+    permits: usize,
+    granted: bool,
+    waker: Option<Waker>,
+}
+
+impl State {
+    /// Grants the free permits to the first waiters, and returns their wakers
+    ///
+    /// NOTE: a waiter needing more permits than available blocks the next ones,
+    ///       so it can't be starved by smaller acquisitions
+    fn grant(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        while let Some(id) = self.queue.front() {
+            let waiter = self.waiters.get_mut(id).unwrap();
+            if waiter.permits > self.permits {
+                break;
+            }
+            self.permits -= waiter.permits;
+            waiter.granted = true;
+            wakers.extend(waiter.waker.take());
+            self.queue.pop_front();
+        }
+        wakers
+    }
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                queue: VecDeque::new(),
+                waiters: HashMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    pub fn available_permits(&self) -> usize {
+        self.state.lock().unwrap().permits
+    }
+
+    /// Adds `permits`, granting them to the waiters first.
+    pub fn add_permits(&self, permits: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.permits += permits;
+        let wakers = state.grant();
+        drop(state);
+
+        wakers.into_iter().for_each(Waker::wake);
+    }
+
+    /// Waits for a permit.
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits for `permits` permits, acquired all at once.
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+        }
+    }
+
+    /// Acquires a permit if one is free, and nobody waits for it already.
+    pub fn try_acquire(&self) -> Option<SemaphorePermit<'_>> {
+        self.try_acquire_many(1)
+    }
+
+    pub fn try_acquire_many(&self, permits: usize) -> Option<SemaphorePermit<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.queue.is_empty() || state.permits < permits {
+            return None;
+        }
+        state.permits -= permits;
+        Some(SemaphorePermit {
+            semaphore: self,
+            permits,
+        })
+    }
+}
+
+/// Future returned by `Semaphore::acquire`
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+
+    /// Id of the waiter, once queued
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(coop::poll_proceed(cx));
+
+        let this = self.get_mut();
+        let mut state = this.semaphore.state.lock().unwrap();
+        match this.id {
+            None if state.queue.is_empty() && state.permits >= this.permits => {
+                state.permits -= this.permits;
+            }
+            None => {
+                let id = state.next_id;
+                state.next_id += 1;
+                state.waiters.insert(
+                    id,
+                    Waiter {
+                        permits: this.permits,
+                        granted: false,
+                        waker: Some(cx.waker().clone()),
+                    },
+                );
+                state.queue.push_back(id);
+                this.id = Some(id);
+                return Poll::Pending;
+            }
+            Some(id) => {
+                let waiter = state.waiters.get_mut(&id).unwrap();
+                if !waiter.granted {
+                    waiter.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                state.waiters.remove(&id);
+                this.id = None;
+            }
+        }
+
+        Poll::Ready(SemaphorePermit {
+            semaphore: this.semaphore,
+            permits: this.permits,
+        })
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        let id = match self.id {
+            Some(id) => id,
+            None => return,
+        };
+
+        let mut state = self.semaphore.state.lock().unwrap();
+        let waiter = state.waiters.remove(&id).unwrap();
+        if waiter.granted {
+            // NOTE: granted but never taken, so the permits go to the next waiters
+            state.permits += waiter.permits;
+        } else {
+            state.queue.retain(|&queued| queued != id);
+        }
+        // Removing the first waiter may unblock the next ones too
+        let wakers = state.grant();
+        drop(state);
+
+        wakers.into_iter().for_each(Waker::wake);
+    }
+}
+
+/// Permits acquired from a `Semaphore`, released on drop
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Drops the permit without releasing it, the semaphore has one permit less.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, poll};
+
+    #[test]
+    fn waiters_are_served_in_order_even_if_one_is_dropped() {
+        let semaphore = Semaphore::new(2);
+        block_on(async {
+            let held = semaphore.acquire_many(2).await;
+
+            let mut first = Box::pin(semaphore.acquire_many(2));
+            let mut cancelled = Box::pin(semaphore.acquire());
+            let mut last = Box::pin(semaphore.acquire());
+            assert!(poll!(first.as_mut()).is_pending());
+            assert!(poll!(cancelled.as_mut()).is_pending());
+            assert!(poll!(last.as_mut()).is_pending());
+            // The first waiter gets both permits,
+            // and nobody can jump the queue of the next ones
+            drop(held);
+            assert!(semaphore.try_acquire().is_none());
+            let first = first.await;
+            assert!(poll!(last.as_mut()).is_pending());
+
+            // A granted waiter dropped before being polled gives its permit back
+            drop(first);
+            drop(cancelled);
+            let last = last.await;
+            assert_eq!(semaphore.available_permits(), 1);
+            drop(last);
+        });
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+}
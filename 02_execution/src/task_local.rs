@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: task-local values, e.g. to carry a request id through the futures of a task.
+//       A value is owned by the future returned by `LocalKey::scope`, and moved into
+//       a thread-local slot for the duration of each of its polls, so it follows the task
+//       whatever the thread or the executor polling it
+
+use std::{
+    cell::RefCell,
+    fmt,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+};
+
+/// Declares task-local keys, of type `LocalKey<T>`:
+///
+/// ```
+/// _02_execution::task_local! {
+///     pub static REQUEST_ID: u64;
+/// }
+/// ```
+#[macro_export]
+macro_rules! task_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::task_local::LocalKey<$t> = {
+            ::std::thread_local! {
+                static SLOT: ::std::cell::RefCell<::std::option::Option<$t>> =
+                    const { ::std::cell::RefCell::new(::std::option::Option::None) };
+            }
+            $crate::task_local::LocalKey { slot: SLOT }
+        };
+        $crate::task_local!($($rest)*);
+    };
+}
+
+/// A key to a task-local value, declared with `task_local!`.
+pub struct LocalKey<T: 'static> {
+    /// The value of the task being polled on the current thread
+    #[doc(hidden)]
+    pub slot: thread::LocalKey<RefCell<Option<T>>>,
+}
+
+/// The task-local value isn't set, since the caller isn't polled
+/// within a `LocalKey::scope`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessError;
+
+impl fmt::Display for AccessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("task-local value not set")
+    }
+}
+
+impl std::error::Error for AccessError {}
+
+impl<T: 'static> LocalKey<T> {
+    /// Sets the value of the key to `value` while the `future` is polled.
+    pub fn scope<F: Future>(&'static self, value: T, future: F) -> TaskLocalFuture<T, F> {
+        TaskLocalFuture {
+            key: self,
+            value: Some(value),
+            future,
+        }
+    }
+
+    /// Calls `f` with the value of the key.
+    ///
+    /// Panics if the value isn't set, see `try_with`.
+    pub fn with<R>(&'static self, f: impl FnOnce(&T) -> R) -> R {
+        self.try_with(f)
+            .expect("task-local value not set, is the future polled within `LocalKey::scope`?")
+    }
+
+    /// Calls `f` with the value of the key, if it's set.
+    pub fn try_with<R>(&'static self, f: impl FnOnce(&T) -> R) -> Result<R, AccessError> {
+        self.slot.with(|slot| match &*slot.borrow() {
+            Some(value) => Ok(f(value)),
+            None => Err(AccessError),
+        })
+    }
+}
+
+impl<T: Clone + 'static> LocalKey<T> {
+    /// Returns a copy of the value of the key.
+    ///
+    /// Panics if the value isn't set, see `try_with`.
+    pub fn get(&'static self) -> T {
+        self.with(T::clone)
+    }
+}
+
+/// A future with a task-local value set while it's polled, see `LocalKey::scope`
+pub struct TaskLocalFuture<T: 'static, F> {
+    key: &'static LocalKey<T>,
+
+    /// The value, while the future isn't polled
+    value: Option<T>,
+
+    future: F,
+}
+
+/// Moves the value back out of the thread-local slot on drop,
+/// even if the poll panics
+struct Restore<'a, T: 'static> {
+    key: &'static LocalKey<T>,
+    value: &'a mut Option<T>,
+}
+
+impl<T: 'static> Drop for Restore<'_, T> {
+    fn drop(&mut self) {
+        // NOTE: swapped rather than taken, so a value of an outer scope
+        //       of the same key is restored too
+        self.key
+            .slot
+            .with(|slot| mem::swap(self.value, &mut *slot.borrow_mut()));
+    }
+}
+
+impl<T: 'static, F: Future> Future for TaskLocalFuture<T, F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the `future` is never moved out of `self`,
+        //         only the `value` is, and it's never pinned
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        let value = &mut this.value;
+
+        this.key
+            .slot
+            .with(|slot| mem::swap(value, &mut *slot.borrow_mut()));
+        let _restore = Restore {
+            key: this.key,
+            value,
+        };
+        future.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::new_executor_and_spawner, timer_future::TimerFuture};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    crate::task_local! {
+        static REQUEST_ID: u32;
+    }
+
+    #[test]
+    fn each_task_sees_its_own_value() {
+        let (executor, spawner) = new_executor_and_spawner();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+
+        for request_id in 0..3 {
+            let seen = seen.clone();
+            let future = async move {
+                let before = REQUEST_ID.get();
+                // The tasks interleave while they wait
+                TimerFuture::new(Duration::from_millis(10 * (3 - request_id as u64))).await;
+                let after = REQUEST_ID.get();
+
+                let nested = REQUEST_ID.scope(42, async { REQUEST_ID.get() }).await;
+                seen.lock()
+                    .unwrap()
+                    .push((before, after, nested, REQUEST_ID.get()));
+            };
+            spawner.spawn(REQUEST_ID.scope(request_id, future)).unwrap();
+        }
+        drop(spawner);
+        executor.run();
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [(2, 2, 42, 2), (1, 1, 42, 1), (0, 0, 42, 0)]
+        );
+        assert_eq!(REQUEST_ID.try_with(|_| ()), Err(AccessError));
+    }
+}
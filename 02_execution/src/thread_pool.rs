@@ -0,0 +1,337 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: a multi-threaded variant of the `Executor` from 2.3. Applied: Build an Executor
+//       Every worker has its own run queue, tasks spawned from the outside go to
+//       a global injector queue, and idle workers steal tasks from the busy ones
+
+use {
+    crate::{
+        executor::{Hooks, Schedule, SlowPoll, Spawner, TaskRef, TaskRegistry},
+        metrics::MetricsSnapshot,
+        timer_future::TimerDriver,
+    },
+    crossbeam_deque::{Injector, Stealer, Worker},
+    std::{
+        any::Any,
+        cell::RefCell,
+        iter, mem,
+        sync::{
+            atomic::{self, AtomicBool, AtomicUsize, Ordering},
+            Arc, Condvar, Mutex,
+        },
+        thread,
+        time::Duration,
+    },
+};
+
+/// Task executor that runs tasks on several worker threads.
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+    workers: Vec<Worker<TaskRef>>,
+    registry: Arc<TaskRegistry>,
+
+    /// Fires the `TimerFuture`s created by the tasks of this pool.
+    timer: TimerDriver,
+
+    hooks: Hooks,
+}
+
+/// State shared by the workers and the `Scheduler`
+struct Shared {
+    /// Tasks spawned or woken outside of the workers
+    injector: Injector<TaskRef>,
+
+    /// Handles to steal tasks from the run queue of each worker
+    stealers: Vec<Stealer<TaskRef>>,
+
+    /// Set once every `Spawner` and `Task` is dropped, so no task can be queued anymore
+    closed: AtomicBool,
+
+    /// Number of workers waiting for a task on `condvar`
+    sleeping: AtomicUsize,
+    lock: Mutex<()>,
+    condvar: Condvar,
+}
+
+/// Run queue of the worker running on the current thread
+struct Current {
+    shared: Arc<Shared>,
+    local: Worker<TaskRef>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Current>> = const { RefCell::new(None) };
+}
+
+/// Schedules woken tasks onto the pool.
+///
+/// Owned by the `Spawner`s and the `Task`s, so the pool is closed
+/// when the last of them is dropped.
+struct Scheduler {
+    shared: Arc<Shared>,
+}
+
+pub fn new_thread_pool_and_spawner(worker_count: usize) -> (ThreadPool, Spawner) {
+    assert!(worker_count > 0, "a thread pool needs at least one worker");
+
+    let workers = iter::repeat_with(Worker::new_fifo)
+        .take(worker_count)
+        .collect::<Vec<_>>();
+    let shared = Arc::new(Shared {
+        injector: Injector::new(),
+        stealers: workers.iter().map(Worker::stealer).collect(),
+        closed: AtomicBool::new(false),
+        sleeping: AtomicUsize::new(0),
+        lock: Mutex::new(()),
+        condvar: Condvar::new(),
+    });
+    let registry = Arc::new(TaskRegistry::new());
+    let pool = ThreadPool {
+        shared: shared.clone(),
+        workers,
+        registry: registry.clone(),
+        timer: TimerDriver::new(),
+        hooks: Hooks::default(),
+    };
+    let spawner = Spawner::new(Arc::new(Scheduler { shared }), registry);
+    (pool, spawner)
+}
+
+impl Schedule for Scheduler {
+    fn schedule(&self, task: TaskRef) {
+        // A task woken by a worker goes to the run queue of that worker
+        let task = CURRENT.with(|current| match &*current.borrow() {
+            Some(current) if Arc::ptr_eq(&current.shared, &self.shared) => {
+                current.local.push(task);
+                None
+            }
+            _ => Some(task),
+        });
+        if let Some(task) = task {
+            self.shared.injector.push(task);
+        }
+
+        self.shared.notify_one();
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        let _lock = self.shared.lock.lock().unwrap();
+        self.shared.condvar.notify_all();
+    }
+}
+
+impl Shared {
+    fn notify_one(&self) {
+        // NOTE: `sleeping` is incremented under the lock before a worker checks
+        //       the queues for the last time, so either that worker sees the task,
+        //       or we see it sleeping and wake it up
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock().unwrap();
+            self.condvar.notify_one();
+        }
+    }
+
+    fn has_tasks(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+
+    /// Takes a task from the global injector, or steals it from another worker
+    fn steal(&self, local: &Worker<TaskRef>) -> Option<TaskRef> {
+        iter::repeat_with(|| {
+            self.injector.steal_batch_and_pop(local).or_else(|| {
+                self.stealers
+                    .iter()
+                    .map(Stealer::steal)
+                    .collect::<crossbeam_deque::Steal<_>>()
+            })
+        })
+        .find(|steal| !steal.is_retry())
+        .and_then(|steal| steal.success())
+    }
+}
+
+impl ThreadPool {
+    /// Sets the hook called when a task panics, see `Executor::set_panic_hook`.
+    pub fn set_panic_hook(&mut self, hook: impl Fn(&(dyn Any + Send)) + Send + Sync + 'static) {
+        self.hooks.panic = Some(Arc::new(hook));
+    }
+
+    /// Reports the polls taking longer than the `threshold`,
+    /// see `Executor::set_slow_poll_threshold`.
+    pub fn set_slow_poll_threshold(&mut self, threshold: Duration) {
+        self.hooks.slow_poll_threshold = Some(threshold);
+    }
+
+    /// Sets the hook called with the slow polls, see `Executor::set_slow_poll_hook`.
+    pub fn set_slow_poll_hook(&mut self, hook: impl Fn(&SlowPoll) + Send + Sync + 'static) {
+        self.hooks.slow_poll = Some(Arc::new(hook));
+    }
+
+    /// Limits the number of live tasks, see `Executor::set_max_tasks`.
+    pub fn set_max_tasks(&self, max_tasks: usize) {
+        self.registry.set_max(max_tasks);
+    }
+
+    /// Returns a snapshot of the metrics of the pool, see `Executor::metrics`.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.registry.metrics()
+    }
+
+    /// Runs the tasks on the worker threads until every `Spawner`
+    /// and every task is dropped.
+    pub fn run(mut self) {
+        let workers = mem::take(&mut self.workers);
+        let (shared, timer, hooks) = (&self.shared, &self.timer, &self.hooks);
+
+        thread::scope(|scope| {
+            for (index, local) in workers.into_iter().enumerate() {
+                let shared = shared.clone();
+                thread::Builder::new()
+                    .name(format!("worker-{}", index))
+                    .spawn_scoped(scope, move || {
+                        // Make the `TimerFuture`s created while polling register
+                        // in our timer driver
+                        let _timer = timer.enter();
+                        run_worker(shared, local, hooks);
+                    })
+                    .expect("failed to spawn a worker thread");
+            }
+        });
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // NOTE: the queued tasks hold the pool itself (through their scheduler),
+        //       so they would leak, along with their futures, if it was never run.
+        //       They are queued once more to drop their futures on this thread
+        self.registry.cancel_all();
+        loop {
+            let task = iter::repeat_with(|| self.shared.injector.steal())
+                .find(|steal| !steal.is_retry())
+                .and_then(|steal| steal.success())
+                .or_else(|| self.workers.iter().find_map(Worker::pop));
+            match task {
+                Some(task) => task.run(&self.hooks),
+                None => break,
+            }
+        }
+    }
+}
+
+fn run_worker(shared: Arc<Shared>, local: Worker<TaskRef>, hooks: &Hooks) {
+    CURRENT.with(|current| {
+        *current.borrow_mut() = Some(Current {
+            shared: shared.clone(),
+            local,
+        })
+    });
+
+    while let Some(task) = next_task(&shared) {
+        task.run(hooks);
+    }
+
+    CURRENT.with(|current| current.borrow_mut().take());
+}
+
+/// Waits for the next task to run, or returns `None` once the pool is closed
+fn next_task(shared: &Shared) -> Option<TaskRef> {
+    loop {
+        let task = CURRENT.with(|current| {
+            let current = current.borrow();
+            let local = &current.as_ref().unwrap().local;
+            local.pop().or_else(|| shared.steal(local))
+        });
+        if task.is_some() {
+            return task;
+        }
+
+        let lock = shared.lock.lock().unwrap();
+        shared.sleeping.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        if !shared.has_tasks() {
+            if shared.closed.load(Ordering::SeqCst) {
+                shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+            drop(shared.condvar.wait(lock).unwrap());
+        }
+        shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{executor::Priority, timer_future::TimerFuture};
+    use std::collections::HashSet;
+
+    #[test]
+    fn spreads_tasks_across_workers() {
+        let (pool, spawner) = new_thread_pool_and_spawner(4);
+        let threads = Arc::new(Mutex::new(HashSet::new()));
+
+        for _ in 0..64 {
+            let threads = threads.clone();
+            spawner
+                .spawn(async move {
+                    // Keep the worker busy, so the others have to steal
+                    thread::sleep(Duration::from_millis(5));
+                    TimerFuture::new(Duration::from_millis(1)).await;
+                    threads.lock().unwrap().insert(thread::current().id());
+                })
+                .unwrap();
+        }
+        drop(spawner);
+        pool.run();
+
+        assert!(threads.lock().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn dropping_a_pool_drops_its_tasks() {
+        let (pool, spawner) = new_thread_pool_and_spawner(2);
+        let guard = Arc::new(());
+
+        let task_guard = guard.clone();
+        let task = spawner
+            .spawn(async move {
+                let _guard = task_guard;
+            })
+            .unwrap();
+        drop(spawner);
+        drop(pool);
+
+        assert_eq!(Arc::strong_count(&guard), 1);
+        assert!(futures::executor::block_on(task)
+            .unwrap_err()
+            .is_cancelled());
+    }
+
+    #[test]
+    fn priorities_are_ignored() {
+        let (pool, spawner) = new_thread_pool_and_spawner(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        for priority in [Priority::Background, Priority::Normal, Priority::High] {
+            let order = order.clone();
+            spawner
+                .spawn_with_priority(priority, async move {
+                    order.lock().unwrap().push(priority);
+                })
+                .unwrap();
+        }
+        drop(spawner);
+        pool.run();
+
+        // In the order they were spawned, the single worker has no classes
+        assert_eq!(
+            *order.lock().unwrap(),
+            [Priority::Background, Priority::Normal, Priority::High]
+        );
+    }
+}
@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// NOTE: bounds how long a future may run with a `TimerFuture` racing it. Since timers
+//       fall back to a process-wide driver, it works outside of our executors too
+//       (e.g. on async-std)
+
+use {
+    crate::timer_future::TimerFuture,
+    std::{
+        fmt,
+        future::Future,
+        pin::Pin,
+        task::{Context, Poll},
+        time::{Duration, Instant},
+    },
+};
+
+/// The timer of a `Timeout` fired before its future completed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("deadline has elapsed")
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+/// Runs the `future` for at most the `duration`.
+///
+/// Returns `Err(Elapsed)` if it didn't complete in time, the future is dropped then.
+/// A `Duration::MAX` never elapses, e.g. for a timeout that can be disabled.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Some(future),
+        timer: TimerFuture::new(duration),
+    }
+}
+
+/// Runs the `future` until the `deadline` at most, see `timeout`.
+///
+/// The `deadline` should come from `Handle::now`, see `TimerFuture::at`.
+pub fn deadline<F: Future>(deadline: Instant, future: F) -> Timeout<F> {
+    Timeout {
+        future: Some(future),
+        timer: TimerFuture::at(deadline),
+    }
+}
+
+/// Future returned by `timeout` and `deadline`
+pub struct Timeout<F> {
+    /// `None` once the timer fired
+    future: Option<F>,
+    timer: TimerFuture,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // SAFETY: the `future` is never moved out of `self`, only dropped in place,
+        //         and the `timer` is `Unpin`
+        let this = unsafe { self.get_unchecked_mut() };
+        let mut future = unsafe { Pin::new_unchecked(&mut this.future) };
+
+        // NOTE: the future goes first, so it wins if both are ready
+        let inner = future
+            .as_mut()
+            .as_pin_mut()
+            .expect("`Timeout` polled after completion");
+        if let Poll::Ready(output) = inner.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+
+        match Pin::new(&mut this.timer).poll(cx) {
+            Poll::Ready(()) => {
+                future.set(None);
+                Poll::Ready(Err(Elapsed))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        simulation::new_simulation_and_spawner,
+        timer_future::{Handle, TimerDriver},
+    };
+    use futures::{executor::block_on, future};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn elapses_and_drops_the_future_if_the_timer_fires_first() {
+        struct SetOnDrop(Arc<AtomicBool>);
+
+        impl Drop for SetOnDrop {
+            fn drop(&mut self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let (mut simulation, spawner) = new_simulation_and_spawner(0);
+        let dropped = Arc::new(AtomicBool::new(false));
+        let guard = SetOnDrop(dropped.clone());
+        let flag = dropped.clone();
+
+        let results = spawner
+            .spawn(async move {
+                let slow = timeout(Duration::from_secs(10), async move {
+                    let _guard = guard;
+                    TimerFuture::new(Duration::from_secs(60)).await;
+                });
+                let mut timed_out = Box::pin(slow);
+                let elapsed = (&mut timed_out).await;
+                // Dropped on expiry, not only along with the `Timeout`
+                let dropped_on_expiry = flag.load(Ordering::SeqCst);
+
+                let fast = deadline(
+                    // On the virtual clock of the simulation
+                    Handle::current().now() + Duration::from_secs(60),
+                    TimerFuture::new(Duration::from_secs(1)),
+                )
+                .await;
+                (elapsed, dropped_on_expiry, fast)
+            })
+            .unwrap();
+        simulation.run();
+
+        assert_eq!(block_on(results).unwrap(), (Err(Elapsed), true, Ok(())));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn the_longest_duration_never_elapses() {
+        let (mut simulation, spawner) = new_simulation_and_spawner(0);
+        let results = spawner
+            .spawn(async {
+                let ready = timeout(Duration::MAX, async { "ready" }).await;
+                let later = timeout(
+                    Duration::MAX,
+                    TimerFuture::new(Duration::from_secs(24 * 60 * 60)),
+                )
+                .await;
+                (ready, later)
+            })
+            .unwrap();
+        simulation.run();
+
+        assert_eq!(block_on(results).unwrap(), (Ok("ready"), Ok(())));
+    }
+
+    #[test]
+    fn works_outside_of_our_executors() {
+        let result = block_on(timeout(
+            Duration::from_millis(10),
+            futures::future::pending::<()>(),
+        ));
+        assert_eq!(result, Err(Elapsed));
+    }
+
+    #[test]
+    fn deadlines_from_the_driver_clock_hold_across_a_pause() {
+        let driver = TimerDriver::new();
+        let _driver = driver.enter();
+        let handle = Handle::current();
+
+        handle.pause();
+        thread::sleep(Duration::from_millis(300));
+        handle.resume();
+
+        // The clock is 300ms behind `Instant::now()` from now on
+        let start = Instant::now();
+        block_on(TimerFuture::at(handle.now() + Duration::from_millis(10)));
+        let elapsed = block_on(deadline(
+            handle.now() + Duration::from_millis(10),
+            future::pending::<()>(),
+        ));
+        assert_eq!(elapsed, Err(Elapsed));
+        assert!(start.elapsed() < Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,644 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// https://rust-lang.github.io/async-book/02_execution/03_wakeups.html
+// 2.2. Task Wakeups with Waker
+
+use crate::{coop, local_executor};
+use std::{
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, OnceLock},
+    task::{ready, Context, Poll, Waker},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+pub struct TimerFuture {
+    shared_state: Arc<Mutex<SharedState>>,
+
+    /// Driver the deadline is registered in
+    handle: Handle,
+
+    /// Key of the current registration, `None` once cancelled
+    key: Option<u64>,
+}
+
+/// Shared state between the future and the waiting thread
+struct SharedState {
+    /// Whether or not the sleep time has elapsed
+    completed: bool,
+
+    /// The waker for the task that `TimerFuture` is running on.
+    /// The thread can use this after setting `completed = true` to tell
+    /// `TimerFuture`'s task to wake up, see that `completed = true`, and
+    /// move forward.
+    waker: Option<Waker>,
+}
+
+impl Future for TimerFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        ready!(coop::poll_proceed(cx));
+
+        // Look at the shared state to see if the timer has already completed.
+        let mut shared_state = self.shared_state.lock().unwrap();
+        if shared_state.completed {
+            Poll::Ready(())
+        } else {
+            // Set waker so that the thread can wake up the current task
+            // when the timer has completed, ensuring that the future is polled
+            // again and sees that `completed = true`.
+            //
+            // It's tempting to do this once rather than repeatedly cloning
+            // the waker each time. However, the `TimerFuture` can move between
+            // tasks on the executor, which could cause a stale waker pointing
+            // to the wrong task, preventing `TimerFuture` from waking up
+            // correctly.
+            //
+            // N.B. it's possible to check for this using the `Waker::will_wake`
+            // function, but we omit that here to keep things simple.
+            shared_state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl TimerFuture {
+    /// Create a new `TimerFuture` which will complete after the provided
+    /// timeout.
+    ///
+    /// A timeout too long for an `Instant` (e.g. `Duration::MAX`) never elapses.
+    pub fn new(duration: Duration) -> Self {
+        let handle = Handle::current();
+        let deadline = handle.now().checked_add(duration);
+        Self::new_in(handle, deadline)
+    }
+
+    /// Create a new `TimerFuture` which will complete at the `deadline`.
+    ///
+    /// The `deadline` is on the clock of the current driver, so it should be
+    /// computed from `Handle::current().now()` rather than `Instant::now()`:
+    /// the two differ once the clock was paused or advanced.
+    pub fn at(deadline: Instant) -> Self {
+        Self::new_in(Handle::current(), Some(deadline))
+    }
+
+    /// Creates a timer completing at the `deadline`, or never if there is none
+    fn new_in(handle: Handle, deadline: Option<Instant>) -> Self {
+        let shared_state = Arc::new(Mutex::new(SharedState {
+            completed: false,
+            waker: None,
+        }));
+
+        // NOTE: the course spawns a new thread per timer here. That does not scale
+        //       to thousands of timers, so we register the deadline in the timer
+        //       driver of the current executor instead
+        let key = deadline.map(|deadline| handle.register(deadline, shared_state.clone()));
+
+        TimerFuture {
+            shared_state,
+            handle,
+            key,
+        }
+    }
+
+    /// Deregisters the deadline, so the timer never fires.
+    ///
+    /// A cancelled timer stays pending until it is re-armed with `reset`.
+    pub fn cancel(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.handle.deregister(key);
+        }
+    }
+
+    /// Re-arms the timer to complete at the `new_deadline`,
+    /// whether it has already completed, been cancelled or is still pending.
+    ///
+    /// Like for `at`, the `new_deadline` is on the clock of the driver, see `Handle::now`.
+    pub fn reset(&mut self, new_deadline: Instant) {
+        self.cancel();
+        self.shared_state.lock().unwrap().completed = false;
+        self.key = Some(
+            self.handle
+                .register(new_deadline, self.shared_state.clone()),
+        );
+    }
+}
+
+impl Drop for TimerFuture {
+    fn drop(&mut self) {
+        // NOTE: otherwise the driver would keep the deadline and wake up
+        //       a task that may be long gone
+        self.cancel();
+    }
+}
+
+/// A deadline registered in the timer driver
+struct Entry {
+    deadline: Instant,
+
+    /// Registration order, so timers with the same deadline fire in FIFO order.
+    /// Also the key of the registration in `Timers::active`.
+    seq: u64,
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // NOTE: `BinaryHeap` is a max-heap, so the order is reversed
+        //       to keep the earliest deadline on the top
+        (other.deadline, other.seq).cmp(&(self.deadline, self.seq))
+    }
+}
+
+struct Timers {
+    heap: BinaryHeap<Entry>,
+
+    /// Registrations that are neither fired nor cancelled yet.
+    ///
+    /// Cancelled registrations are only removed from here, their heap entries
+    /// are skipped once they reach the top (or dropped by `compact`).
+    active: HashMap<u64, Arc<Mutex<SharedState>>>,
+
+    next_seq: u64,
+    shutdown: bool,
+}
+
+impl Timers {
+    /// Pops the deadlines up to `now`, and returns the wakers of their timers
+    fn expire(&mut self, now: Instant) -> Vec<Waker> {
+        let mut wakers = Vec::new();
+        while self.heap.peek().is_some_and(|entry| entry.deadline <= now) {
+            let entry = self.heap.pop().unwrap();
+            // Skip the deadlines of cancelled timers
+            if let Some(shared_state) = self.active.remove(&entry.seq) {
+                let mut shared_state = shared_state.lock().unwrap();
+                // Signal that the timer has completed and take the waker of
+                // the last task on which the future was polled, if one exists.
+                //
+                // NOTE: `completed` is set while holding the lock, so a concurrent
+                //       `reset` can't be overwritten by an outdated deadline
+                shared_state.completed = true;
+                wakers.extend(shared_state.waker.take());
+            }
+        }
+        wakers
+    }
+
+    /// Drops the heap entries of cancelled registrations once they make up
+    /// the most of the heap, so frequently cancelled timers don't pile up
+    fn compact(&mut self) {
+        if self.heap.len() > 2 * self.active.len() + 64 {
+            let active = &self.active;
+            self.heap.retain(|entry| active.contains_key(&entry.seq));
+        }
+    }
+}
+
+/// Time source of a timer driver: it goes on with the real time, unless it's paused
+struct Clock {
+    /// The real time when the clock last went on (or was created)
+    real_base: Instant,
+
+    /// The time of the clock at `real_base`, so it goes on from where it was paused
+    base: Instant,
+
+    /// The time while the clock is paused, it only moves forward when advanced
+    paused: Option<Instant>,
+}
+
+impl Clock {
+    fn now(&self) -> Instant {
+        self.paused
+            .unwrap_or_else(|| self.base + self.real_base.elapsed())
+    }
+}
+
+struct Inner {
+    timers: Mutex<Timers>,
+
+    /// NOTE: always locked after `timers`, when both are needed
+    clock: Mutex<Clock>,
+
+    /// Signalled when the earliest deadline changes or the driver shuts down
+    condvar: Condvar,
+}
+
+impl Inner {
+    fn run(&self) {
+        let mut timers = self.timers.lock().unwrap();
+        loop {
+            if timers.shutdown {
+                return;
+            }
+
+            let clock = self.clock.lock().unwrap();
+            let (now, paused) = (clock.now(), clock.paused.is_some());
+            drop(clock);
+            if paused {
+                // The timers only fire when the paused clock is advanced
+                timers = self.condvar.wait(timers).unwrap();
+                continue;
+            }
+            let wakers = timers.expire(now);
+
+            if !wakers.is_empty() {
+                // NOTE: wakers are called without holding the lock, since waking
+                //       a task may block (e.g. on a full task channel) while
+                //       the task itself tries to register a new timer
+                drop(timers);
+                wakers.into_iter().for_each(Waker::wake);
+                timers = self.timers.lock().unwrap();
+                continue;
+            }
+
+            timers = match timers.heap.peek() {
+                Some(entry) => {
+                    let timeout = entry.deadline - now;
+                    self.condvar.wait_timeout(timers, timeout).unwrap().0
+                }
+                None => self.condvar.wait(timers).unwrap(),
+            };
+        }
+    }
+}
+
+/// Handle to register deadlines in a `TimerDriver`
+#[derive(Clone)]
+pub struct Handle {
+    inner: Arc<Inner>,
+}
+
+thread_local! {
+    /// Timer driver of the executor running on the current thread
+    static CURRENT: RefCell<Option<Handle>> = const { RefCell::new(None) };
+}
+
+impl Handle {
+    /// Returns the handle of the timer driver entered on the current thread,
+    /// or the one of a process-wide driver if there is none
+    /// (e.g. when a `TimerFuture` is created outside of our `Executor`).
+    pub fn current() -> Self {
+        static DEFAULT: OnceLock<TimerDriver> = OnceLock::new();
+
+        CURRENT
+            .with(|current| current.borrow().clone())
+            .unwrap_or_else(|| DEFAULT.get_or_init(TimerDriver::new).handle())
+    }
+
+    /// Returns the current time of the clock of the driver.
+    ///
+    /// NOTE: it falls behind `Instant::now()` while paused and doesn't catch up
+    ///       on resume, and `advance` moves it ahead. The deadlines given to our
+    ///       timer APIs are compared against this clock, not the real time
+    pub fn now(&self) -> Instant {
+        self.inner.clock.lock().unwrap().now()
+    }
+
+    /// Freezes the clock of the driver, e.g. so tests don't have to wait
+    /// for their timers: they only fire when the clock is `advance`d.
+    pub fn pause(&self) {
+        let _timers = self.inner.timers.lock().unwrap();
+        let mut clock = self.inner.clock.lock().unwrap();
+        clock.paused = Some(clock.now());
+    }
+
+    /// Lets the paused clock go on with the real time, from where it was paused.
+    pub fn resume(&self) {
+        // NOTE: the driver thread checks the clock with `timers` locked,
+        //       so it can't miss the notification
+        let _timers = self.inner.timers.lock().unwrap();
+        let mut clock = self.inner.clock.lock().unwrap();
+        if let Some(paused) = clock.paused.take() {
+            clock.real_base = Instant::now();
+            clock.base = paused;
+            self.inner.condvar.notify_one();
+        }
+    }
+
+    /// Moves the paused clock forward by the `duration` at once, and fires
+    /// the timers whose deadlines passed, in the order of their deadlines.
+    ///
+    /// Panics if the clock isn't paused.
+    pub fn advance(&self, duration: Duration) {
+        let target = self
+            .inner
+            .clock
+            .lock()
+            .unwrap()
+            .paused
+            .expect("the clock must be paused to be advanced")
+            + duration;
+
+        while self.fire_next_deadline(Some(target)) {}
+        self.inner.clock.lock().unwrap().paused = Some(target);
+    }
+
+    /// Moves the paused clock to the earliest deadline, if it isn't after the `limit`,
+    /// and fires the timers due then.
+    ///
+    /// Returns `false` if there is no such deadline.
+    fn fire_next_deadline(&self, limit: Option<Instant>) -> bool {
+        let mut timers = self.inner.timers.lock().unwrap();
+        // Also drops the cancelled deadlines on the top
+        while timers
+            .heap
+            .peek()
+            .is_some_and(|entry| !timers.active.contains_key(&entry.seq))
+        {
+            timers.heap.pop();
+        }
+        let deadline = match timers.heap.peek() {
+            Some(entry) if limit.is_none_or(|limit| entry.deadline <= limit) => entry.deadline,
+            _ => return false,
+        };
+
+        let mut clock = self.inner.clock.lock().unwrap();
+        let now = clock
+            .paused
+            .expect("the clock must be paused to be advanced")
+            .max(deadline);
+        clock.paused = Some(now);
+        let wakers = timers.expire(now);
+        drop((clock, timers));
+
+        // NOTE: woken outside of the locks, see `Inner::run`
+        wakers.into_iter().for_each(Waker::wake);
+        true
+    }
+
+    fn register(&self, deadline: Instant, shared_state: Arc<Mutex<SharedState>>) -> u64 {
+        let mut timers = self.inner.timers.lock().unwrap();
+        let seq = timers.next_seq;
+        timers.next_seq += 1;
+        timers.heap.push(Entry { deadline, seq });
+        timers.active.insert(seq, shared_state);
+
+        // Only wake the driver thread up if its sleep time has to be shortened
+        if timers.heap.peek().map(|entry| entry.seq) == Some(seq) {
+            self.inner.condvar.notify_one();
+        }
+
+        seq
+    }
+
+    fn deregister(&self, key: u64) {
+        let mut timers = self.inner.timers.lock().unwrap();
+        if timers.active.remove(&key).is_some() {
+            timers.compact();
+        }
+    }
+}
+
+/// A timer driver firing all the registered `TimerFuture`s
+/// from a single background thread.
+pub struct TimerDriver {
+    handle: Handle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Inner {
+    fn new(paused: Option<Instant>) -> Arc<Self> {
+        let now = Instant::now();
+        Arc::new(Inner {
+            timers: Mutex::new(Timers {
+                heap: BinaryHeap::new(),
+                active: HashMap::new(),
+                next_seq: 0,
+                shutdown: false,
+            }),
+            clock: Mutex::new(Clock {
+                real_base: now,
+                base: now,
+                paused,
+            }),
+            condvar: Condvar::new(),
+        })
+    }
+}
+
+impl TimerDriver {
+    pub fn new() -> Self {
+        let inner = Inner::new(None);
+
+        let thread_inner = inner.clone();
+        let thread = thread::Builder::new()
+            .name("timer-driver".into())
+            .spawn(move || {
+                local_executor::allow_local_wakeups();
+                thread_inner.run()
+            })
+            .expect("failed to spawn the timer driver thread");
+
+        TimerDriver {
+            handle: Handle { inner },
+            thread: Some(thread),
+        }
+    }
+
+    /// Creates a driver with a paused clock, and without a thread: its timers
+    /// only fire when the clock is advanced, see `advance_to_next_deadline`.
+    ///
+    /// NOTE: so its clock must never be resumed
+    pub(crate) fn new_virtual() -> Self {
+        TimerDriver {
+            handle: Handle {
+                inner: Inner::new(Some(Instant::now())),
+            },
+            thread: None,
+        }
+    }
+
+    /// Moves the paused clock to the earliest deadline, and fires the timers due then.
+    ///
+    /// Returns `false` if there is no deadline left.
+    pub(crate) fn advance_to_next_deadline(&self) -> bool {
+        self.handle.fire_next_deadline(None)
+    }
+
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Makes `TimerFuture`s created on the current thread register in this driver
+    /// until the returned guard is dropped.
+    pub fn enter(&self) -> EnterGuard {
+        let previous = CURRENT.with(|current| current.replace(Some(self.handle())));
+        EnterGuard { previous }
+    }
+}
+
+impl Default for TimerDriver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for TimerDriver {
+    fn drop(&mut self) {
+        self.handle.inner.timers.lock().unwrap().shutdown = true;
+        self.handle.inner.condvar.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Restores the previously entered timer driver on drop
+pub struct EnterGuard {
+    previous: Option<Handle>,
+}
+
+impl Drop for EnterGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, future::join_all};
+
+    #[test]
+    fn fires_many_timers_from_one_thread() {
+        let driver = TimerDriver::new();
+        let _enter = driver.enter();
+
+        let start = Instant::now();
+        let timers = (0..100_000)
+            .map(|i| TimerFuture::new(Duration::from_millis(i % 50)))
+            .collect::<Vec<_>>();
+        block_on(join_all(timers));
+
+        assert!(start.elapsed() >= Duration::from_millis(49));
+    }
+
+    #[test]
+    fn dropped_timers_are_deregistered() {
+        let driver = TimerDriver::new();
+        let _enter = driver.enter();
+
+        let timers = (0..1_000)
+            .map(|_| TimerFuture::new(Duration::from_secs(60)))
+            .collect::<Vec<_>>();
+        drop(timers);
+
+        let timers = driver.handle.inner.timers.lock().unwrap();
+        assert!(timers.active.is_empty());
+        assert!(timers.heap.len() <= 64);
+    }
+
+    #[test]
+    fn reset_rearms_a_cancelled_timer() {
+        let driver = TimerDriver::new();
+        let _enter = driver.enter();
+
+        let mut timer = TimerFuture::new(Duration::from_secs(60));
+        timer.cancel();
+        timer.reset(Instant::now() + Duration::from_millis(10));
+        block_on(&mut timer);
+
+        timer.reset(Instant::now() + Duration::from_millis(10));
+        block_on(timer);
+    }
+
+    #[test]
+    fn a_timeout_too_long_for_an_instant_never_elapses() {
+        let driver = TimerDriver::new();
+        let _enter = driver.enter();
+
+        let mut timer = TimerFuture::new(Duration::MAX);
+        let waker = futures::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+        assert!(driver.handle.inner.timers.lock().unwrap().active.is_empty());
+
+        // It can still be re-armed
+        timer.reset(Instant::now() + Duration::from_millis(10));
+        block_on(timer);
+    }
+
+    #[test]
+    fn advancing_a_paused_clock_fires_the_timers_in_order() {
+        struct RecordWake {
+            id: u64,
+            woken: Arc<Mutex<Vec<u64>>>,
+        }
+
+        impl futures::task::ArcWake for RecordWake {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.woken.lock().unwrap().push(arc_self.id);
+            }
+        }
+
+        let driver = TimerDriver::new();
+        let _enter = driver.enter();
+        let handle = Handle::current();
+        handle.pause();
+
+        let woken = Arc::new(Mutex::new(Vec::new()));
+        let mut timers = [3, 1, 2, 60]
+            .iter()
+            .map(|&secs| {
+                let waker = futures::task::waker(Arc::new(RecordWake {
+                    id: secs,
+                    woken: woken.clone(),
+                }));
+                let mut timer = TimerFuture::new(Duration::from_secs(secs));
+                let mut cx = Context::from_waker(&waker);
+                assert!(Pin::new(&mut timer).poll(&mut cx).is_pending());
+                timer
+            })
+            .collect::<Vec<_>>();
+
+        let start = handle.now();
+        handle.advance(Duration::from_millis(1500));
+        assert_eq!(*woken.lock().unwrap(), [1]);
+        handle.advance(Duration::from_secs(2));
+        assert_eq!(*woken.lock().unwrap(), [1, 2, 3]);
+        assert_eq!(handle.now() - start, Duration::from_millis(3500));
+
+        for timer in &mut timers[..3] {
+            block_on(timer);
+        }
+
+        // Back to the real time, from where the clock was paused
+        handle.resume();
+        block_on(TimerFuture::new(Duration::from_millis(10)));
+        assert!(handle.now() - start >= Duration::from_millis(3510));
+    }
+
+    #[test]
+    fn a_resumed_clock_goes_on_from_where_it_was_paused() {
+        let driver = TimerDriver::new();
+        let handle = driver.handle();
+
+        handle.pause();
+        let paused = handle.now();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(handle.now(), paused);
+
+        // The real time spent paused isn't caught up on
+        handle.resume();
+        assert!(handle.now() - paused < Duration::from_millis(50));
+    }
+}
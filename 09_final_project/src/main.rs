@@ -22,30 +22,38 @@
 // 2) https://github.com/s373r/course-rust-async-book/compare/9.1..9.2
 // 3) https://github.com/s373r/course-rust-async-book/compare/9.2..9.3
 
+use _02_execution::blocking::spawn_blocking;
+use _02_execution::executor::new_executor_and_spawner;
+use _02_execution::net::TcpListener;
+use _02_execution::timer_future::TimerFuture;
 use async_std::io::{Read, Write};
-use async_std::net::TcpListener;
 use async_std::prelude::*;
-use async_std::task;
-use async_std::task::spawn;
-use futures::stream::StreamExt;
 use std::fs;
 use std::marker::Unpin;
 use std::time::Duration;
 
-#[async_std::main]
-async fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").await.unwrap();
-    listener
-        .incoming()
-        // NOTE: use None as a limit we create N threads in a reactor
-        //       to process incoming connections
-        //       , where N is CPU count
-        .for_each_concurrent(/* limit */ None, |stream| async move {
-            let stream = stream.unwrap();
-            // NOTE: spawn a task not a thread
-            spawn(handle_connection(stream));
+// NOTE: the server runs on our executor from 2.3. Applied: Build an Executor,
+//       with the TCP types of its reactor, instead of `#[async_std::main]`
+fn main() {
+    let (executor, spawner) = new_executor_and_spawner();
+
+    let connection_spawner = spawner.clone();
+    spawner
+        .spawn(async move {
+            // Bound from a task, so the listener registers in the reactor of our executor
+            let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                // NOTE: spawn a task not a thread
+                connection_spawner
+                    .spawn(handle_connection(stream))
+                    .expect("failed to spawn a connection task");
+            }
         })
-        .await;
+        .expect("failed to spawn the accept loop");
+    drop(spawner);
+
+    executor.run();
 }
 
 async fn handle_connection(mut stream: impl Read + Write + Unpin) {
@@ -61,12 +69,17 @@ async fn handle_connection(mut stream: impl Read + Write + Unpin) {
     let (status_line, filename) = if buffer.starts_with(get) {
         ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
     } else if buffer.starts_with(sleep) {
-        task::sleep(Duration::from_secs(5)).await;
+        TimerFuture::new(Duration::from_secs(5)).await;
         ("HTTP/1.1 200 OK\r\n\r\n", "hello.html")
     } else {
         ("HTTP/1.1 404 NOT FOUND\r\n\r\n", "404.html")
     };
-    let contents = fs::read_to_string(filename).unwrap();
+    // NOTE: the file is read on the blocking pool, so the executor thread
+    //       keeps serving the other connections meanwhile
+    let contents = spawn_blocking(move || fs::read_to_string(filename))
+        .await
+        .unwrap()
+        .unwrap();
 
     // Write response back to the stream,
     // and flush the stream to ensure the response is sent back to the client
@@ -75,66 +88,128 @@ async fn handle_connection(mut stream: impl Read + Write + Unpin) {
     stream.flush().await.unwrap();
 }
 
-use futures::io::Error;
-use futures::task::{Context, Poll};
-use std::cmp::min;
-use std::pin::Pin;
-
-struct MockTcpStream {
-    read_data: Vec<u8>,
-    write_data: Vec<u8>,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Error;
+    use futures::task::{Context, Poll};
+    use std::cmp::min;
+    use std::pin::Pin;
+
+    struct MockTcpStream {
+        read_data: Vec<u8>,
+        write_data: Vec<u8>,
+    }
 
-impl Unpin for MockTcpStream {}
+    impl Unpin for MockTcpStream {}
 
-impl Read for MockTcpStream {
-    fn poll_read(
-        self: Pin<&mut Self>,
-        _: &mut Context,
-        buf: &mut [u8],
-    ) -> Poll<Result<usize, Error>> {
-        let size: usize = min(self.read_data.len(), buf.len());
-        buf[..size].copy_from_slice(&self.read_data[..size]);
+    impl Read for MockTcpStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize, Error>> {
+            let size: usize = min(self.read_data.len(), buf.len());
+            buf[..size].copy_from_slice(&self.read_data[..size]);
 
-        Poll::Ready(Ok(size))
+            Poll::Ready(Ok(size))
+        }
     }
-}
 
-impl Write for MockTcpStream {
-    fn poll_write(
-        mut self: Pin<&mut Self>,
-        _: &mut Context,
-        buf: &[u8],
-    ) -> Poll<Result<usize, Error>> {
-        self.write_data = Vec::from(buf);
+    impl Write for MockTcpStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context,
+            buf: &[u8],
+        ) -> Poll<Result<usize, Error>> {
+            self.write_data = Vec::from(buf);
 
-        Poll::Ready(Ok(buf.len()))
-    }
+            Poll::Ready(Ok(buf.len()))
+        }
 
-    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
-        Poll::Ready(Ok(()))
-    }
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
 
-    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
-        Poll::Ready(Ok(()))
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), Error>> {
+            Poll::Ready(Ok(()))
+        }
     }
-}
 
-#[async_std::test]
-async fn test_handle_connection() {
-    let input_bytes = b"GET / HTTP/1.1\r\n";
-    let mut contents = vec![0u8; 1024];
-    contents[..input_bytes.len()].clone_from_slice(input_bytes);
-    let mut stream = MockTcpStream {
-        read_data: contents,
-        write_data: Vec::new(),
-    };
+    #[async_std::test]
+    async fn test_handle_connection() {
+        let input_bytes = b"GET / HTTP/1.1\r\n";
+        let mut contents = vec![0u8; 1024];
+        contents[..input_bytes.len()].clone_from_slice(input_bytes);
+        let mut stream = MockTcpStream {
+            read_data: contents,
+            write_data: Vec::new(),
+        };
+
+        handle_connection(&mut stream).await;
+        let mut buf = [0u8; 1024];
+        stream.read(&mut buf).await.unwrap();
+
+        let expected_contents = fs::read_to_string("hello.html").unwrap();
+        let expected_response = format!("HTTP/1.1 200 OK\r\n\r\n{}", expected_contents);
+        assert!(stream.write_data.starts_with(expected_response.as_bytes()));
+    }
 
-    handle_connection(&mut stream).await;
-    let mut buf = [0u8; 1024];
-    stream.read(&mut buf).await.unwrap();
+    #[test]
+    fn test_sleep_route_with_a_paused_clock() {
+        use _02_execution::timer_future::{Handle, TimerDriver};
+        use futures::executor::block_on;
+
+        let driver = TimerDriver::new();
+        let _enter = driver.enter();
+        let clock = Handle::current();
+        clock.pause();
+
+        let mut stream = MockTcpStream {
+            read_data: b"GET /sleep HTTP/1.1\r\n".to_vec(),
+            write_data: Vec::new(),
+        };
+        block_on(async {
+            let mut connection = Box::pin(handle_connection(&mut stream));
+            assert!(futures::poll!(connection.as_mut()).is_pending());
+            // No need to wait five seconds
+            clock.advance(Duration::from_secs(5));
+            connection.await;
+        });
+
+        assert!(stream.write_data.starts_with(b"HTTP/1.1 200 OK"));
+    }
 
-    let expected_contents = fs::read_to_string("hello.html").unwrap();
-    let expected_response = format!("HTTP/1.1 200 OK\r\n\r\n{}", expected_contents);
-    assert!(stream.write_data.starts_with(expected_response.as_bytes()));
+    #[test]
+    fn test_concurrent_connections_in_simulation() {
+        use _02_execution::simulation::{check, new_simulation_and_spawner};
+        use std::sync::{Arc, Mutex};
+
+        check(0..32, |seed| {
+            let (mut simulation, spawner) = new_simulation_and_spawner(seed);
+            let responses = Arc::new(Mutex::new(Vec::new()));
+            for request in [&b"GET / HTTP/1.1\r\n"[..], b"GET /missing HTTP/1.1\r\n"].repeat(2) {
+                let responses = responses.clone();
+                spawner
+                    .spawn(async move {
+                        let mut stream = MockTcpStream {
+                            read_data: request.to_vec(),
+                            write_data: Vec::new(),
+                        };
+                        handle_connection(&mut stream).await;
+                        responses.lock().unwrap().push(stream.write_data);
+                    })
+                    .unwrap();
+            }
+            simulation.run();
+
+            let responses = responses.lock().unwrap();
+            assert_eq!(responses.len(), 4);
+            let ok = responses
+                .iter()
+                .filter(|response| response.starts_with(b"HTTP/1.1 200 OK"))
+                .count();
+            assert_eq!(ok, 2);
+        });
+    }
 }